@@ -3,7 +3,17 @@ pub const REWARD_POOL_KEY: &[u8] = b"rewardpool";
 pub const TOKEN_INFO_KEY: &[u8] = b"tokeninfo";
 pub const VIEWING_KEY_KEY: &[u8] = b"viewingkey";
 pub const SUBSCRIBERS_KEY: &[u8] = b"subscribers";
+pub const RESIDUAL_KEY: &[u8] = b"residual";
+pub const LAST_ALLOCATION_KEY: &[u8] = b"lastallocation";
+pub const REFERRERS_KEY: &[u8] = b"referrers";
+pub const REFERRALS_KEY: &[u8] = b"referrals";
+pub const USER_CHECKPOINTS_KEY: &[u8] = b"usercheckpoints";
+pub const TOTAL_CHECKPOINTS_KEY: &[u8] = b"totalcheckpoints";
+pub const VOTING_POWER_CHECKPOINTS_KEY: &[u8] = b"votingpowercheckpoints";
+pub const DELEGATES_KEY: &[u8] = b"delegates";
+pub const DELEGATED_POWER_KEY: &[u8] = b"delegatedpower";
 
 pub const RESPONSE_BLOCK_SIZE: usize = 256;
 
 pub const REWARD_SCALE: u128 = 1_000_000_000_000_000_000; // 10 ^ 18
+pub const REFERRAL_SHARE_SCALE: u128 = 10_000; // Basis points
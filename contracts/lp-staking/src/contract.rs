@@ -10,13 +10,20 @@ use secret_toolkit::utils::{pad_handle_result, pad_query_result};
 
 use crate::constants::*;
 use crate::querier::query_pending;
-use crate::state::Config;
+use crate::state::{
+    read_delegate, read_delegated_power, read_referral_info, read_referrer, read_total_checkpoint,
+    read_user_checkpoint, read_voting_power_checkpoint, store_delegate, store_delegated_power,
+    store_referral_info, store_referrer, write_total_checkpoint, write_user_checkpoint,
+    write_voting_power_checkpoint, Config,
+};
 use scrt_finance::lp_staking_msg::LPStakingResponseStatus::Success;
 use scrt_finance::lp_staking_msg::{
     LPStakingHandleAnswer, LPStakingHandleMsg, LPStakingHookMsg, LPStakingInitMsg,
-    LPStakingQueryAnswer, LPStakingQueryMsg, LPStakingReceiveAnswer, LPStakingReceiveMsg,
+    LPStakingPoolStatus, LPStakingQueryAnswer, LPStakingQueryMsg, LPStakingReceiveAnswer,
+    LPStakingReceiveMsg,
 };
 use scrt_finance::master_msg::MasterHandleMsg;
+use scrt_finance::math::mul_div;
 use scrt_finance::secret_vote_types::PollFactoryHandleMsg;
 use scrt_finance::types::{RewardPool, SecretContract, TokenInfo, UserInfo};
 use scrt_finance::viewing_key::{ViewingKey, VIEWING_KEY_SIZE};
@@ -40,9 +47,21 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
             prng_seed: prng_seed_hashed.to_vec(),
             is_stopped: false,
             own_addr: env.contract.address,
+            reward_start_block: msg.reward_start_block.unwrap_or(0),
+            reward_end_block: msg.reward_end_block,
+            referral_share: msg.referral_share.unwrap_or(0),
         },
     )?;
 
+    if msg.referral_share.unwrap_or(0) as u128 > REFERRAL_SHARE_SCALE {
+        return Err(StdError::generic_err(
+            "referral share can't be more than 10000 basis points",
+        ));
+    }
+    validate_reward_blocks(msg.reward_start_block.unwrap_or(0), msg.reward_end_block)?;
+
+    TypedStoreMut::<u128, S>::attach(&mut deps.storage).store(RESIDUAL_KEY, &0)?;
+
     TypedStoreMut::<RewardPool, S>::attach(&mut deps.storage).store(
         REWARD_POOL_KEY,
         &RewardPool {
@@ -125,6 +144,8 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             create_viewing_key(deps, env, entropy)
         }
         LPStakingHandleMsg::SetViewingKey { key, .. } => set_viewing_key(deps, env, key),
+        LPStakingHandleMsg::Delegate { to } => delegate(deps, env, to),
+        LPStakingHandleMsg::Undelegate {} => undelegate(deps, env),
         LPStakingHandleMsg::StopContract {} => stop_contract(deps, env),
         LPStakingHandleMsg::ChangeAdmin { address } => change_admin(deps, env, address),
         LPStakingHandleMsg::NotifyAllocation { amount } => {
@@ -138,6 +159,15 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
         LPStakingHandleMsg::RemoveRewardSources { contracts } => {
             remove_reward_sources(deps, env, contracts)
         }
+        LPStakingHandleMsg::SetRewardBlocks {
+            start_block,
+            end_block,
+            remove_end_block,
+        } => set_reward_blocks(deps, env, start_block, end_block, remove_end_block),
+        LPStakingHandleMsg::ClaimResidual { recipient, amount } => {
+            claim_residual(deps, env, recipient, amount)
+        }
+        LPStakingHandleMsg::SetReferralShare { share } => set_referral_share(deps, env, share),
         LPStakingHandleMsg::SelfCallback { message } => self_callback(deps, env, message),
         _ => Err(StdError::generic_err("Unavailable or unknown action")),
     };
@@ -158,6 +188,8 @@ pub fn query<S: Storage, A: Api, Q: Querier>(
         LPStakingQueryMsg::Subscribers {} => query_subscribers(deps),
         LPStakingQueryMsg::RewardSources {} => query_reward_sources(deps),
         LPStakingQueryMsg::Admin {} => query_admin(deps),
+        LPStakingQueryMsg::PoolStatus { block } => query_pool_status(deps, block),
+        LPStakingQueryMsg::TotalLockedAt { block } => query_total_locked_at(deps, block),
         _ => authenticated_queries(deps, msg),
     };
 
@@ -183,6 +215,17 @@ pub fn authenticated_queries<S: Storage, A: Api, Q: Querier>(
                 address, height, ..
             } => query_pending_rewards(deps, &address, height),
             LPStakingQueryMsg::Balance { address, .. } => query_deposit(deps, &address),
+            LPStakingQueryMsg::ReferralStats { address, .. } => {
+                query_referral_stats(deps, &address)
+            }
+            LPStakingQueryMsg::BalanceAt { address, block, .. } => {
+                query_deposit_at(deps, &address, block)
+            }
+            LPStakingQueryMsg::VotingPower { address, .. } => query_voting_power(deps, &address),
+            LPStakingQueryMsg::VotingPowerAt { address, block, .. } => {
+                query_voting_power_at(deps, &address, block)
+            }
+            LPStakingQueryMsg::Delegation { address, .. } => query_delegation(deps, &address),
             _ => panic!("This should never happen"),
         };
     }
@@ -204,7 +247,7 @@ fn receive<S: Storage, A: Api, Q: Querier>(
     let msg: LPStakingReceiveMsg = from_binary(&msg)?;
 
     match msg {
-        LPStakingReceiveMsg::Deposit {} => deposit(deps, env, from, amount),
+        LPStakingReceiveMsg::Deposit { referrer } => deposit(deps, env, from, amount, referrer),
     }
 }
 
@@ -225,11 +268,33 @@ fn notify_allocation<S: Storage, A: Api, Q: Querier>(
         ));
     }
 
-    update_rewards(deps, amount)?;
+    // Admin allocations have no block range, so they are judged by the current block alone
+    let mut last_allocation_store = PrefixedStorage::new(LAST_ALLOCATION_KEY, &mut deps.storage);
+    let mut last_allocation_store = TypedStoreMut::<u64, _>::attach(&mut last_allocation_store);
+    let last_allocation_block = if env.message.sender != config.admin {
+        let last = last_allocation_store.may_load(env.message.sender.0.as_bytes())?;
+        last_allocation_store.store(env.message.sender.0.as_bytes(), &env.block.height)?;
+        last
+    } else {
+        None
+    };
+
+    let (active, residual) =
+        split_allocation(&config, last_allocation_block, env.block.height, amount)?;
+    if residual > 0 {
+        let mut residual_store = TypedStoreMut::<u128, S>::attach(&mut deps.storage);
+        let total_residual = residual_store.load(RESIDUAL_KEY).unwrap_or(0);
+        residual_store.store(RESIDUAL_KEY, &(total_residual + residual))?;
+    }
+
+    update_rewards(deps, active)?;
 
     Ok(HandleResponse {
         messages: vec![],
-        log: vec![log("notify_allocation", env.message.sender.to_string())],
+        log: vec![
+            log("notify_allocation", env.message.sender.to_string()),
+            log("residual", residual.to_string()),
+        ],
         data: None,
     })
 }
@@ -247,9 +312,19 @@ fn self_callback<S: Storage, A: Api, Q: Querier>(
     let config = TypedStore::<Config, S>::attach(&deps.storage).load(CONFIG_KEY)?;
 
     match message {
-        LPStakingHookMsg::Deposit { from, amount } => {
-            deposit_hook(deps, env, config, reward_pool, from, amount.u128())
-        }
+        LPStakingHookMsg::Deposit {
+            from,
+            amount,
+            referrer,
+        } => deposit_hook(
+            deps,
+            env,
+            config,
+            reward_pool,
+            from,
+            amount.u128(),
+            referrer,
+        ),
         LPStakingHookMsg::Redeem { to, amount } => {
             redeem_hook(deps, env, config, reward_pool, to, amount)
         }
@@ -261,6 +336,7 @@ fn deposit<S: Storage, A: Api, Q: Querier>(
     env: Env,
     from: HumanAddr,
     amount: u128,
+    referrer: Option<HumanAddr>,
 ) -> StdResult<HandleResponse> {
     // Ensure that the sent tokens are from an expected contract address
     let config = TypedStore::<Config, S>::attach(&deps.storage).load(CONFIG_KEY)?;
@@ -271,57 +347,73 @@ fn deposit<S: Storage, A: Api, Q: Querier>(
         )));
     }
 
+    if config.has_ended(env.block.height) {
+        return Err(StdError::generic_err(
+            "this pool has ended and does not accept new deposits",
+        ));
+    }
+
     update_allocation(
         env,
         config,
         Some(LPStakingHookMsg::Deposit {
             from,
             amount: Uint128(amount),
+            referrer,
         }),
     )
 }
 
 fn deposit_hook<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    _env: Env,
+    env: Env,
     config: Config,
     mut reward_pool: RewardPool,
     from: HumanAddr,
     amount: u128,
+    referrer: Option<HumanAddr>,
 ) -> StdResult<HandleResponse> {
     let mut messages: Vec<CosmosMsg> = vec![];
-    let mut users_store = TypedStoreMut::<UserInfo, S>::attach(&mut deps.storage);
-    let mut user = users_store
+    let mut user = TypedStore::<UserInfo, S>::attach(&deps.storage)
         .load(from.0.as_bytes())
         .unwrap_or(UserInfo { locked: 0, debt: 0 }); // NotFound is the only possible error
 
     if user.locked > 0 {
-        let pending = user.locked * reward_pool.acc_reward_per_share / REWARD_SCALE - user.debt;
-        if pending > 0 {
-            messages.push(secret_toolkit::snip20::transfer_msg(
-                from.clone(),
-                Uint128(pending),
-                None,
-                RESPONSE_BLOCK_SIZE,
-                config.reward_token.contract_hash,
-                config.reward_token.address,
-            )?);
+        let pending = pending_rewards(&user, reward_pool.acc_reward_per_share)?;
+        messages.extend(harvest_msgs(deps, &config, &from, pending)?);
+    } else if let Some(referrer) = referrer {
+        let referrer_raw = deps.api.canonical_address(&referrer)?;
+        // Referrers can only be attached to new stakers, so existing stake can't be re-referred
+        if referrer_raw != deps.api.canonical_address(&from)?
+            && read_referrer(&deps.storage, &from)?.is_none()
+        {
+            store_referrer(&mut deps.storage, &from, &referrer_raw)?;
+            let referrer = deps.api.human_address(&referrer_raw)?;
+
+            let mut referral_info = read_referral_info(&deps.storage, &referrer)?;
+            referral_info.referees += 1;
+            store_referral_info(&mut deps.storage, &referrer, &referral_info)?;
         }
     }
 
     user.locked += amount;
-    user.debt = user.locked * reward_pool.acc_reward_per_share / REWARD_SCALE;
-    users_store.store(from.0.as_bytes(), &user)?;
+    user.debt = accrued_rewards(user.locked, reward_pool.acc_reward_per_share)?;
+    TypedStoreMut::<UserInfo, S>::attach(&mut deps.storage).store(from.0.as_bytes(), &user)?;
+
+    update_referred_stake(deps, &from, amount, true)?;
+    let voter = update_delegated_power(deps, &from, amount, true)?;
 
     reward_pool.inc_token_supply += amount;
     TypedStoreMut::attach(&mut deps.storage).store(REWARD_POOL_KEY, &reward_pool)?;
 
-    let subs: Vec<SecretContract> = TypedStore::attach(&deps.storage).load(SUBSCRIBERS_KEY)?;
-    let sub_messages: StdResult<Vec<CosmosMsg>> = subs
-        .into_iter()
-        .map(|s| create_subscriber_msg(s, &from, user.locked))
-        .collect();
-    messages.extend(sub_messages?);
+    write_user_checkpoint(&mut deps.storage, &from, env.block.height, user.locked)?;
+    write_total_checkpoint(
+        &mut deps.storage,
+        env.block.height,
+        reward_pool.inc_token_supply,
+    )?;
+
+    messages.extend(voting_power_msgs(deps, env.block.height, &[voter])?);
 
     Ok(HandleResponse {
         messages,
@@ -350,7 +442,7 @@ fn redeem<S: Storage, A: Api, Q: Querier>(
 
 fn redeem_hook<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    _env: Env,
+    env: Env,
     config: Config,
     mut reward_pool: RewardPool,
     to: HumanAddr,
@@ -369,27 +461,28 @@ fn redeem_hook<S: Storage, A: Api, Q: Querier>(
     }
 
     let mut messages: Vec<CosmosMsg> = vec![];
-    let pending = user.locked * reward_pool.acc_reward_per_share / REWARD_SCALE - user.debt;
-    if pending > 0 {
-        // Transfer rewards
-        messages.push(secret_toolkit::snip20::transfer_msg(
-            to.clone(),
-            Uint128(pending),
-            None,
-            RESPONSE_BLOCK_SIZE,
-            config.reward_token.contract_hash,
-            config.reward_token.address,
-        )?);
-    }
+    let pending = pending_rewards(&user, reward_pool.acc_reward_per_share)?;
+    // Transfer rewards
+    messages.extend(harvest_msgs(deps, &config, &to, pending)?);
 
     // Transfer redeemed tokens
     user.locked -= amount;
-    user.debt = user.locked * reward_pool.acc_reward_per_share / REWARD_SCALE;
+    user.debt = accrued_rewards(user.locked, reward_pool.acc_reward_per_share)?;
     TypedStoreMut::<UserInfo, S>::attach(&mut deps.storage).store(to.0.as_bytes(), &user)?;
 
+    update_referred_stake(deps, &to, amount, false)?;
+    let voter = update_delegated_power(deps, &to, amount, false)?;
+
     reward_pool.inc_token_supply -= amount;
     TypedStoreMut::attach(&mut deps.storage).store(REWARD_POOL_KEY, &reward_pool)?;
 
+    write_user_checkpoint(&mut deps.storage, &to, env.block.height, user.locked)?;
+    write_total_checkpoint(
+        &mut deps.storage,
+        env.block.height,
+        reward_pool.inc_token_supply,
+    )?;
+
     messages.push(secret_toolkit::snip20::transfer_msg(
         to.clone(),
         Uint128(amount),
@@ -399,12 +492,7 @@ fn redeem_hook<S: Storage, A: Api, Q: Querier>(
         config.inc_token.address,
     )?);
 
-    let subs: Vec<SecretContract> = TypedStore::attach(&deps.storage).load(SUBSCRIBERS_KEY)?;
-    let sub_messages: StdResult<Vec<CosmosMsg>> = subs
-        .into_iter()
-        .map(|s| create_subscriber_msg(s, &to, user.locked))
-        .collect();
-    messages.extend(sub_messages?);
+    messages.extend(voting_power_msgs(deps, env.block.height, &[voter])?);
 
     Ok(HandleResponse {
         messages,
@@ -454,6 +542,54 @@ pub fn set_viewing_key<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn delegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    to: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let delegator = env.message.sender;
+    if deps.api.canonical_address(&to)? == deps.api.canonical_address(&delegator)? {
+        return Err(StdError::generic_err("you can't delegate to yourself"));
+    }
+
+    let prev_delegate = remove_delegation(deps, &delegator)?;
+
+    let user = TypedStore::<UserInfo, S>::attach(&deps.storage)
+        .load(delegator.0.as_bytes())
+        .unwrap_or(UserInfo { locked: 0, debt: 0 });
+    store_delegate(&mut deps.storage, &delegator, Some(&to))?;
+    let delegated_power = read_delegated_power(&deps.storage, &to)?;
+    store_delegated_power(&mut deps.storage, &to, delegated_power + user.locked)?;
+
+    let mut voters = vec![delegator.clone(), to.clone()];
+    voters.extend(prev_delegate.filter(|d| *d != to));
+
+    Ok(HandleResponse {
+        messages: voting_power_msgs(deps, env.block.height, &voters)?,
+        log: vec![],
+        data: Some(to_binary(&LPStakingHandleAnswer::Delegate {
+            status: Success,
+        })?),
+    })
+}
+
+fn undelegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let delegator = env.message.sender;
+    let prev_delegate = remove_delegation(deps, &delegator)?
+        .ok_or_else(|| StdError::generic_err("you haven't delegated your voting power"))?;
+
+    Ok(HandleResponse {
+        messages: voting_power_msgs(deps, env.block.height, &[delegator, prev_delegate])?,
+        log: vec![],
+        data: Some(to_binary(&LPStakingHandleAnswer::Undelegate {
+            status: Success,
+        })?),
+    })
+}
+
 fn stop_contract<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
@@ -533,6 +669,16 @@ fn emergency_redeem<S: Storage, A: Api, Q: Querier>(
     reward_pool.inc_token_supply -= user.locked;
     TypedStoreMut::attach(&mut deps.storage).store(REWARD_POOL_KEY, &reward_pool)?;
 
+    update_referred_stake(deps, &env.message.sender, user.locked, false)?;
+    let voter = update_delegated_power(deps, &env.message.sender, user.locked, false)?;
+
+    write_user_checkpoint(&mut deps.storage, &env.message.sender, env.block.height, 0)?;
+    write_total_checkpoint(
+        &mut deps.storage,
+        env.block.height,
+        reward_pool.inc_token_supply,
+    )?;
+
     let mut messages = vec![];
     if user.locked > 0 {
         messages.push(secret_toolkit::snip20::transfer_msg(
@@ -548,6 +694,10 @@ fn emergency_redeem<S: Storage, A: Api, Q: Querier>(
     user = UserInfo { locked: 0, debt: 0 };
     TypedStoreMut::attach(&mut deps.storage).store(env.message.sender.0.as_bytes(), &user)?;
 
+    // Subscribers aren't notified, so a failing factory or poll can't block the emergency exit.
+    // Snapshot polls still see the change through the checkpoint
+    checkpoint_voting_power(deps, &voter, env.block.height)?;
+
     Ok(HandleResponse {
         messages,
         log: vec![],
@@ -653,6 +803,112 @@ fn remove_reward_sources<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn set_reward_blocks<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+    remove_end_block: bool,
+) -> StdResult<HandleResponse> {
+    let mut config_store = TypedStoreMut::attach(&mut deps.storage);
+    let mut config: Config = config_store.load(CONFIG_KEY)?;
+    enforce_admin(&config, env)?;
+
+    if remove_end_block && end_block.is_some() {
+        return Err(StdError::generic_err(
+            "can't set and remove the reward end block at the same time",
+        ));
+    }
+
+    if let Some(start) = start_block {
+        config.reward_start_block = start;
+    }
+
+    if end_block.is_some() || remove_end_block {
+        config.reward_end_block = end_block;
+    }
+
+    validate_reward_blocks(config.reward_start_block, config.reward_end_block)?;
+
+    config_store.store(CONFIG_KEY, &config)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&LPStakingHandleAnswer::SetRewardBlocks {
+            status: Success,
+        })?),
+    })
+}
+
+fn claim_residual<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: Option<HumanAddr>,
+    amount: Option<Uint128>,
+) -> StdResult<HandleResponse> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    enforce_admin(&config, env.clone())?;
+
+    let mut residual_store = TypedStoreMut::<u128, S>::attach(&mut deps.storage);
+    let residual = residual_store.load(RESIDUAL_KEY).unwrap_or(0);
+    let amount = amount.unwrap_or(Uint128(residual)).u128();
+    if amount > residual {
+        return Err(StdError::generic_err(format!(
+            "insufficient residual rewards: residual={}, required={}",
+            residual, amount,
+        )));
+    }
+    residual_store.store(RESIDUAL_KEY, &(residual - amount))?;
+
+    let mut messages = vec![];
+    if amount > 0 {
+        messages.push(secret_toolkit::snip20::transfer_msg(
+            recipient.unwrap_or(env.message.sender),
+            Uint128(amount),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            config.reward_token.contract_hash,
+            config.reward_token.address,
+        )?);
+    }
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![log("claim_residual", amount.to_string())],
+        data: Some(to_binary(&LPStakingHandleAnswer::ClaimResidual {
+            status: Success,
+        })?),
+    })
+}
+
+fn set_referral_share<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    share: u16,
+) -> StdResult<HandleResponse> {
+    let mut config_store = TypedStoreMut::attach(&mut deps.storage);
+    let mut config: Config = config_store.load(CONFIG_KEY)?;
+    enforce_admin(&config, env)?;
+
+    if share as u128 > REFERRAL_SHARE_SCALE {
+        return Err(StdError::generic_err(
+            "referral share can't be more than 10000 basis points",
+        ));
+    }
+
+    config.referral_share = share;
+    config_store.store(CONFIG_KEY, &config)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&LPStakingHandleAnswer::SetReferralShare {
+            status: Success,
+        })?),
+    })
+}
+
 // Query functions
 
 fn query_pending_rewards<S: Storage, A: Api, Q: Querier>(
@@ -660,7 +916,17 @@ fn query_pending_rewards<S: Storage, A: Api, Q: Querier>(
     address: &HumanAddr,
     block: u64,
 ) -> StdResult<Binary> {
-    let new_rewards = query_pending(deps, block)?;
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    let last_allocation_store = ReadonlyPrefixedStorage::new(LAST_ALLOCATION_KEY, &deps.storage);
+    let last_allocation_store = TypedStore::<u64, _>::attach(&last_allocation_store);
+
+    let mut new_rewards = 0;
+    for (source, amount) in query_pending(deps, block)? {
+        let last_allocation_block = last_allocation_store.may_load(source.0.as_bytes())?;
+        let (active, _) = split_allocation(&config, last_allocation_block, block, amount)?;
+        new_rewards = checked_add(new_rewards, active)?;
+    }
+
     let reward_pool = TypedStore::<RewardPool, S>::attach(&deps.storage).load(REWARD_POOL_KEY)?;
     let user = TypedStore::<UserInfo, S>::attach(&deps.storage)
         .load(address.0.as_bytes())
@@ -668,14 +934,17 @@ fn query_pending_rewards<S: Storage, A: Api, Q: Querier>(
     let mut acc_reward_per_share = reward_pool.acc_reward_per_share;
 
     if reward_pool.inc_token_supply != 0 {
-        acc_reward_per_share +=
-            (new_rewards + reward_pool.residue) * REWARD_SCALE / reward_pool.inc_token_supply;
+        let (increment, _) = reward_per_share(
+            checked_add(new_rewards, reward_pool.residue)?,
+            reward_pool.inc_token_supply,
+        )?;
+        acc_reward_per_share = checked_add(acc_reward_per_share, increment)?;
     }
 
     to_binary(&LPStakingQueryAnswer::Rewards {
         // This is not necessarily accurate, since we don't validate new_rewards. It is up to
         // the UI to display accurate numbers
-        rewards: Uint128(user.locked * acc_reward_per_share / REWARD_SCALE - user.debt),
+        rewards: Uint128(pending_rewards(&user, acc_reward_per_share)?),
     })
 }
 
@@ -692,6 +961,64 @@ fn query_deposit<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn query_deposit_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+    block: u64,
+) -> StdResult<Binary> {
+    let amount = read_user_checkpoint(&deps.storage, address, block)?;
+
+    to_binary(&LPStakingQueryAnswer::BalanceAt {
+        amount: Uint128(amount),
+    })
+}
+
+fn query_voting_power<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<Binary> {
+    to_binary(&LPStakingQueryAnswer::VotingPower {
+        amount: Uint128(read_voting_power(deps, address)?),
+    })
+}
+
+fn query_voting_power_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+    block: u64,
+) -> StdResult<Binary> {
+    let amount = read_voting_power_checkpoint(&deps.storage, address, block)?;
+
+    to_binary(&LPStakingQueryAnswer::VotingPowerAt {
+        amount: Uint128(amount),
+    })
+}
+
+fn query_delegation<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<Binary> {
+    to_binary(&LPStakingQueryAnswer::Delegation {
+        delegate: read_delegate(&deps.storage, address)?,
+        delegated_power: Uint128(read_delegated_power(&deps.storage, address)?),
+        voting_power: Uint128(read_voting_power(deps, address)?),
+    })
+}
+
+fn query_referral_stats<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<Binary> {
+    let referral_info = read_referral_info(&deps.storage, address)?;
+
+    to_binary(&LPStakingQueryAnswer::ReferralStats {
+        referrer: load_referrer(deps, address)?,
+        referees: referral_info.referees,
+        referred_stake: Uint128(referral_info.referred_stake),
+        rewards_earned: Uint128(referral_info.rewards_earned),
+    })
+}
+
 fn query_contract_status<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
 ) -> StdResult<Binary> {
@@ -740,6 +1067,17 @@ fn query_total_locked<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) ->
     })
 }
 
+fn query_total_locked_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block: u64,
+) -> StdResult<Binary> {
+    let amount = read_total_checkpoint(&deps.storage, block)?;
+
+    to_binary(&LPStakingQueryAnswer::TotalLockedAt {
+        amount: Uint128(amount),
+    })
+}
+
 fn query_subscribers<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
     let subs: Vec<SecretContract> = TypedStore::attach(&deps.storage).load(SUBSCRIBERS_KEY)?;
 
@@ -756,6 +1094,33 @@ fn query_reward_sources<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn query_pool_status<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block: u64,
+) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    let residual: u128 = TypedStore::attach(&deps.storage)
+        .load(RESIDUAL_KEY)
+        .unwrap_or(0);
+
+    let status = if config.is_stopped {
+        LPStakingPoolStatus::Stopped
+    } else if config.has_ended(block) {
+        LPStakingPoolStatus::Ended
+    } else if block < config.reward_start_block {
+        LPStakingPoolStatus::NotStarted
+    } else {
+        LPStakingPoolStatus::Active
+    };
+
+    to_binary(&LPStakingQueryAnswer::PoolStatus {
+        status,
+        reward_start_block: config.reward_start_block,
+        reward_end_block: config.reward_end_block,
+        residual: Uint128(residual),
+    })
+}
+
 fn query_admin<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
     let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
 
@@ -766,6 +1131,19 @@ fn query_admin<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdRes
 
 // Helper functions
 
+fn validate_reward_blocks(start_block: u64, end_block: Option<u64>) -> StdResult<()> {
+    if let Some(end) = end_block {
+        if end <= start_block {
+            return Err(StdError::generic_err(format!(
+                "reward end block must be after the start block: start={}, end={}",
+                start_block, end
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 fn enforce_admin(config: &Config, env: Env) -> StdResult<()> {
     if config.admin != env.message.sender {
         return Err(StdError::generic_err(format!(
@@ -790,20 +1168,85 @@ fn update_rewards<S: Storage, A: Api, Q: Querier>(
     }
 
     if reward_pool.inc_token_supply == 0 {
-        reward_pool.residue += newly_allocated;
+        reward_pool.residue = checked_add(reward_pool.residue, newly_allocated)?;
         rewards_store.store(REWARD_POOL_KEY, &reward_pool)?;
         return Ok(reward_pool);
     }
 
     // Effectively distributes the residue to the first one that stakes to an empty pool
-    reward_pool.acc_reward_per_share +=
-        (newly_allocated + reward_pool.residue) * REWARD_SCALE / reward_pool.inc_token_supply;
-    reward_pool.residue = 0;
+    let (increment, dust) = reward_per_share(
+        checked_add(newly_allocated, reward_pool.residue)?,
+        reward_pool.inc_token_supply,
+    )?;
+    reward_pool.acc_reward_per_share = checked_add(reward_pool.acc_reward_per_share, increment)?;
+    // Rewards that were lost to rounding are carried over to the next allocation
+    reward_pool.residue = dust;
     rewards_store.store(REWARD_POOL_KEY, &reward_pool)?;
 
     Ok(reward_pool)
 }
 
+/// Splits an allocation that covers the blocks between `from_block` and `to_block` into the part
+/// that was emitted inside the reward window and the part that belongs to the residual bucket
+fn split_allocation(
+    config: &Config,
+    from_block: Option<u64>,
+    to_block: u64,
+    amount: u128,
+) -> StdResult<(u128, u128)> {
+    match from_block {
+        Some(from_block) if to_block > from_block => {
+            let window_start = from_block.max(config.reward_start_block);
+            let window_end = to_block.min(config.reward_end_block.unwrap_or(u64::MAX));
+            let active_blocks = window_end.saturating_sub(window_start);
+
+            let (active, _) = mul_div(
+                amount,
+                active_blocks as u128,
+                (to_block - from_block) as u128,
+            )?;
+            Ok((active, amount - active))
+        }
+        _ => {
+            if config.is_rewarding(to_block) {
+                Ok((amount, 0))
+            } else {
+                Ok((0, amount))
+            }
+        }
+    }
+}
+
+/// Returns the increase in reward per share for distributing `rewards` over `inc_token_supply`,
+/// along with the whole rewards that were left undistributed due to rounding
+fn reward_per_share(rewards: u128, inc_token_supply: u128) -> StdResult<(u128, u128)> {
+    let (increment, remainder) = mul_div(rewards, REWARD_SCALE, inc_token_supply)?;
+
+    Ok((increment, remainder / REWARD_SCALE))
+}
+
+fn accrued_rewards(locked: u128, acc_reward_per_share: u128) -> StdResult<u128> {
+    let (accrued, _) = mul_div(locked, acc_reward_per_share, REWARD_SCALE)?;
+
+    Ok(accrued)
+}
+
+fn pending_rewards(user: &UserInfo, acc_reward_per_share: u128) -> StdResult<u128> {
+    accrued_rewards(user.locked, acc_reward_per_share)?
+        .checked_sub(user.debt)
+        .ok_or_else(|| {
+            StdError::generic_err(format!(
+                "reward debt is larger than accrued rewards: debt={}",
+                user.debt
+            ))
+        })
+}
+
+fn checked_add(a: u128, b: u128) -> StdResult<u128> {
+    a.checked_add(b)
+        .ok_or_else(|| StdError::generic_err(format!("overflow when adding {} and {}", a, b)))
+}
+
 fn update_allocation(
     env: Env,
     config: Config,
@@ -846,6 +1289,172 @@ fn update_allocation(
     })
 }
 
+/// Pays out a staker's pending rewards, routing the referral share to the staker's referrer
+fn harvest_msgs<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    config: &Config,
+    user: &HumanAddr,
+    pending: u128,
+) -> StdResult<Vec<CosmosMsg>> {
+    let mut messages = vec![];
+    if pending == 0 {
+        return Ok(messages);
+    }
+
+    let mut referral_reward = 0;
+    let referrer = load_referrer(deps, user)?;
+    if let Some(referrer) = &referrer {
+        referral_reward = mul_div(pending, config.referral_share as u128, REFERRAL_SHARE_SCALE)?.0;
+        if referral_reward > 0 {
+            let mut referral_info = read_referral_info(&deps.storage, referrer)?;
+            referral_info.rewards_earned += referral_reward;
+            store_referral_info(&mut deps.storage, referrer, &referral_info)?;
+        }
+    }
+
+    if pending > referral_reward {
+        messages.push(secret_toolkit::snip20::transfer_msg(
+            user.clone(),
+            Uint128(pending - referral_reward),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            config.reward_token.contract_hash.clone(),
+            config.reward_token.address.clone(),
+        )?);
+    }
+
+    if let Some(referrer) = referrer.filter(|_| referral_reward > 0) {
+        messages.push(secret_toolkit::snip20::transfer_msg(
+            referrer,
+            Uint128(referral_reward),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            config.reward_token.contract_hash.clone(),
+            config.reward_token.address.clone(),
+        )?);
+    }
+
+    Ok(messages)
+}
+
+fn update_referred_stake<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    user: &HumanAddr,
+    amount: u128,
+    is_deposit: bool,
+) -> StdResult<()> {
+    if let Some(referrer) = load_referrer(deps, user)? {
+        let mut referral_info = read_referral_info(&deps.storage, &referrer)?;
+        if is_deposit {
+            referral_info.referred_stake += amount;
+        } else {
+            referral_info.referred_stake -= amount;
+        }
+        store_referral_info(&mut deps.storage, &referrer, &referral_info)?;
+    }
+
+    Ok(())
+}
+
+fn load_referrer<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    user: &HumanAddr,
+) -> StdResult<Option<HumanAddr>> {
+    read_referrer(&deps.storage, user)?
+        .map(|referrer| deps.api.human_address(&referrer))
+        .transpose()
+}
+
+/// Moves a delegator's stake change to its delegate's aggregate power, and returns the address
+/// whose voting power was affected by the change
+fn update_delegated_power<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    user: &HumanAddr,
+    amount: u128,
+    is_deposit: bool,
+) -> StdResult<HumanAddr> {
+    if let Some(delegate) = read_delegate(&deps.storage, user)? {
+        let mut delegated_power = read_delegated_power(&deps.storage, &delegate)?;
+        if is_deposit {
+            delegated_power += amount;
+        } else {
+            delegated_power -= amount;
+        }
+        store_delegated_power(&mut deps.storage, &delegate, delegated_power)?;
+
+        return Ok(delegate);
+    }
+
+    Ok(user.clone())
+}
+
+fn remove_delegation<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    delegator: &HumanAddr,
+) -> StdResult<Option<HumanAddr>> {
+    let prev_delegate = read_delegate(&deps.storage, delegator)?;
+    if let Some(prev_delegate) = &prev_delegate {
+        let user = TypedStore::<UserInfo, S>::attach(&deps.storage)
+            .load(delegator.0.as_bytes())
+            .unwrap_or(UserInfo { locked: 0, debt: 0 });
+        let delegated_power = read_delegated_power(&deps.storage, prev_delegate)?;
+        store_delegated_power(
+            &mut deps.storage,
+            prev_delegate,
+            delegated_power - user.locked,
+        )?;
+        store_delegate(&mut deps.storage, delegator, None)?;
+    }
+
+    Ok(prev_delegate)
+}
+
+/// An address votes with its own stake, unless it was delegated, plus whatever was delegated to it
+fn read_voting_power<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<u128> {
+    let mut voting_power = read_delegated_power(&deps.storage, address)?;
+    if read_delegate(&deps.storage, address)?.is_none() {
+        let user = TypedStore::<UserInfo, S>::attach(&deps.storage)
+            .load(address.0.as_bytes())
+            .unwrap_or(UserInfo { locked: 0, debt: 0 });
+        voting_power += user.locked;
+    }
+
+    Ok(voting_power)
+}
+
+/// Checkpoints the new voting power of every affected voter and notifies the subscribers of it
+fn voting_power_msgs<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    block: u64,
+    voters: &[HumanAddr],
+) -> StdResult<Vec<CosmosMsg>> {
+    let subs: Vec<SecretContract> = TypedStore::attach(&deps.storage).load(SUBSCRIBERS_KEY)?;
+
+    let mut messages = vec![];
+    for voter in voters {
+        let voting_power = checkpoint_voting_power(deps, voter, block)?;
+        for sub in subs.iter() {
+            messages.push(create_subscriber_msg(sub.clone(), voter, voting_power)?);
+        }
+    }
+
+    Ok(messages)
+}
+
+fn checkpoint_voting_power<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    voter: &HumanAddr,
+    block: u64,
+) -> StdResult<u128> {
+    let voting_power = read_voting_power(deps, voter)?;
+    write_voting_power_checkpoint(&mut deps.storage, voter, block, voting_power)?;
+
+    Ok(voting_power)
+}
+
 fn create_subscriber_msg(
     sub: SecretContract,
     user: &HumanAddr,
@@ -864,11 +1473,15 @@ fn create_subscriber_msg(
 
 #[cfg(test)]
 mod tests {
-    use crate::constants::{CONFIG_KEY, RESPONSE_BLOCK_SIZE, REWARD_POOL_KEY};
+    use crate::constants::{CONFIG_KEY, RESPONSE_BLOCK_SIZE, REWARD_POOL_KEY, REWARD_SCALE};
     use crate::contract::{
-        add_subscribers, create_subscriber_msg, deposit_hook, init, redeem_hook, remove_subscribers,
+        add_subscribers, create_subscriber_msg, delegate, deposit_hook, emergency_redeem, init,
+        redeem_hook, remove_subscribers, split_allocation, undelegate, update_rewards,
+    };
+    use crate::state::{
+        read_referral_info, read_referrer, read_total_checkpoint, read_user_checkpoint,
+        read_voting_power_checkpoint, Config, ReferralInfo,
     };
-    use crate::state::Config;
     use cosmwasm_std::testing::{
         mock_dependencies, mock_env, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
     };
@@ -882,7 +1495,7 @@ mod tests {
     };
     use scrt_finance::secret_vote_types::PollFactoryHandleMsg;
     use scrt_finance::types::{RewardPool, SecretContract, TokenInfo};
-    use secret_toolkit::storage::TypedStore;
+    use secret_toolkit::storage::{TypedStore, TypedStoreMut};
 
     fn init_helper(
         subscribers: Option<Vec<SecretContract>>,
@@ -893,7 +1506,11 @@ mod tests {
         let mut deps = mock_dependencies(20, &[]);
         let env = mock_env("admin", &[]);
 
-        let init_msg = LPStakingInitMsg {
+        (init(&mut deps, env, init_msg(subscribers)), deps)
+    }
+
+    fn init_msg(subscribers: Option<Vec<SecretContract>>) -> LPStakingInitMsg {
+        LPStakingInitMsg {
             reward_token: SecretContract {
                 address: HumanAddr("reward_t".to_string()),
                 contract_hash: "".to_string(),
@@ -913,9 +1530,10 @@ mod tests {
             },
             prng_seed: Default::default(),
             subscribers,
-        };
-
-        (init(&mut deps, env, init_msg), deps)
+            reward_start_block: Some(100),
+            reward_end_block: Some(200),
+            referral_share: Some(1000),
+        }
     }
 
     fn deposit_helper(
@@ -939,6 +1557,7 @@ mod tests {
             reward_pool,
             HumanAddr(addr),
             amount,
+            None,
         )
         .unwrap()
     }
@@ -1150,4 +1769,405 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_split_allocation() {
+        let (init_result, deps) = init_helper(None);
+        assert!(init_result.is_ok());
+
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+
+        // Entirely inside the reward window
+        assert_eq!(
+            split_allocation(&config, Some(120), 150, 300).unwrap(),
+            (300, 0)
+        );
+        // Straddles the start block
+        assert_eq!(
+            split_allocation(&config, Some(50), 150, 300).unwrap(),
+            (150, 150)
+        );
+        // Straddles the end block
+        assert_eq!(
+            split_allocation(&config, Some(180), 220, 400).unwrap(),
+            (200, 200)
+        );
+        // After the pool has ended
+        assert_eq!(
+            split_allocation(&config, Some(250), 300, 500).unwrap(),
+            (0, 500)
+        );
+        // No known range, judged by the current block
+        assert_eq!(split_allocation(&config, None, 150, 500).unwrap(), (500, 0));
+        assert_eq!(split_allocation(&config, None, 200, 500).unwrap(), (0, 500));
+    }
+
+    #[test]
+    fn test_referral_rewards() {
+        let (init_result, mut deps) = init_helper(None);
+        assert!(init_result.is_ok());
+
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        let reward_pool: RewardPool = TypedStore::attach(&deps.storage)
+            .load(REWARD_POOL_KEY)
+            .unwrap();
+        deposit_hook(
+            &mut deps,
+            mock_env("referee", &[]),
+            config.clone(),
+            reward_pool,
+            HumanAddr("referee".to_string()),
+            100,
+            Some(HumanAddr("referrer".to_string())),
+        )
+        .unwrap();
+
+        let mut reward_pool: RewardPool = TypedStore::attach(&deps.storage)
+            .load(REWARD_POOL_KEY)
+            .unwrap();
+        reward_pool.acc_reward_per_share = 10 * REWARD_SCALE;
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(REWARD_POOL_KEY, &reward_pool)
+            .unwrap();
+
+        // Harvest 1000 rewards, 10% of which go to the referrer
+        let result = deposit_helper(&mut deps, "referee".into(), 0);
+        assert_eq!(
+            result.messages,
+            vec![
+                secret_toolkit::snip20::transfer_msg(
+                    HumanAddr("referee".to_string()),
+                    Uint128(900),
+                    None,
+                    RESPONSE_BLOCK_SIZE,
+                    config.reward_token.contract_hash.clone(),
+                    config.reward_token.address.clone(),
+                )
+                .unwrap(),
+                secret_toolkit::snip20::transfer_msg(
+                    HumanAddr("referrer".to_string()),
+                    Uint128(100),
+                    None,
+                    RESPONSE_BLOCK_SIZE,
+                    config.reward_token.contract_hash,
+                    config.reward_token.address,
+                )
+                .unwrap(),
+            ]
+        );
+
+        assert_eq!(
+            read_referrer(&deps.storage, &HumanAddr("referee".to_string())).unwrap(),
+            Some(
+                deps.api
+                    .canonical_address(&HumanAddr("referrer".to_string()))
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            read_referral_info(&deps.storage, &HumanAddr("referrer".to_string())).unwrap(),
+            ReferralInfo {
+                referees: 1,
+                referred_stake: 100,
+                rewards_earned: 100,
+            }
+        );
+
+        // Invalid referrer addresses are rejected instead of being stored
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        let reward_pool: RewardPool = TypedStore::attach(&deps.storage)
+            .load(REWARD_POOL_KEY)
+            .unwrap();
+        let result = deposit_hook(
+            &mut deps,
+            mock_env("newcomer", &[]),
+            config,
+            reward_pool,
+            HumanAddr("newcomer".to_string()),
+            100,
+            Some(HumanAddr("not-a-valid-referrer-address".to_string())),
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            read_referrer(&deps.storage, &HumanAddr("newcomer".to_string())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let (init_result, mut deps) = init_helper(None);
+        assert!(init_result.is_ok());
+
+        let user = HumanAddr("user".to_string());
+        for (height, amount, is_deposit) in vec![(10, 100, true), (20, 50, true), (30, 120, false)]
+        {
+            let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+            let reward_pool: RewardPool = TypedStore::attach(&deps.storage)
+                .load(REWARD_POOL_KEY)
+                .unwrap();
+            let mut env = mock_env(user.clone(), &[]);
+            env.block.height = height;
+
+            if is_deposit {
+                deposit_hook(
+                    &mut deps,
+                    env,
+                    config,
+                    reward_pool,
+                    user.clone(),
+                    amount,
+                    None,
+                )
+                .unwrap();
+            } else {
+                redeem_hook(
+                    &mut deps,
+                    env,
+                    config,
+                    reward_pool,
+                    user.clone(),
+                    Some(Uint128(amount)),
+                )
+                .unwrap();
+            }
+        }
+
+        for (block, expected) in vec![(5, 0), (10, 100), (15, 100), (20, 150), (29, 150), (30, 30)]
+        {
+            assert_eq!(
+                read_user_checkpoint(&deps.storage, &user, block).unwrap(),
+                expected
+            );
+            assert_eq!(
+                read_total_checkpoint(&deps.storage, block).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_delegation() {
+        let sub_a = SecretContract {
+            address: HumanAddr("sub_a".to_string()),
+            contract_hash: "".to_string(),
+        };
+        let alice = HumanAddr("alice".to_string());
+        let bob = HumanAddr("bob".to_string());
+
+        let (init_result, mut deps) = init_helper(Some(vec![sub_a.clone()]));
+        assert!(init_result.is_ok());
+
+        deposit_helper(&mut deps, "alice".into(), 100);
+        deposit_helper(&mut deps, "bob".into(), 50);
+
+        let result = delegate(&mut deps, mock_env("alice", &[]), bob.clone()).unwrap();
+        assert_eq!(
+            result.messages,
+            vec![
+                create_subscriber_msg(sub_a.clone(), &alice, 0).unwrap(),
+                create_subscriber_msg(sub_a.clone(), &bob, 150).unwrap(),
+            ]
+        );
+
+        // Stake changes of the delegator update the delegate's aggregate power
+        let result = deposit_helper(&mut deps, "alice".into(), 20);
+        assert_eq!(
+            result.messages,
+            vec![create_subscriber_msg(sub_a.clone(), &bob, 170).unwrap()]
+        );
+
+        let result = undelegate(&mut deps, mock_env("alice", &[])).unwrap();
+        assert_eq!(
+            result.messages,
+            vec![
+                create_subscriber_msg(sub_a.clone(), &alice, 120).unwrap(),
+                create_subscriber_msg(sub_a, &bob, 50).unwrap(),
+            ]
+        );
+
+        assert!(undelegate(&mut deps, mock_env("alice", &[])).is_err());
+
+        // Delegates must be valid addresses
+        assert!(delegate(
+            &mut deps,
+            mock_env("alice", &[]),
+            HumanAddr("not-a-valid-delegate-address".to_string())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_redelegation_after_emergency_redeem() {
+        let sub_a = SecretContract {
+            address: HumanAddr("sub_a".to_string()),
+            contract_hash: "".to_string(),
+        };
+        let alice = HumanAddr("alice".to_string());
+        let bob = HumanAddr("bob".to_string());
+        let carol = HumanAddr("carol".to_string());
+
+        let (init_result, mut deps) = init_helper(Some(vec![sub_a.clone()]));
+        assert!(init_result.is_ok());
+
+        deposit_helper(&mut deps, "alice".into(), 100);
+        deposit_helper(&mut deps, "bob".into(), 50);
+        deposit_helper(&mut deps, "carol".into(), 30);
+        delegate(&mut deps, mock_env("alice", &[]), bob.clone()).unwrap();
+
+        emergency_redeem(&mut deps, mock_env("alice", &[])).unwrap();
+        let result = deposit_helper(&mut deps, "alice".into(), 40);
+        assert_eq!(
+            result.messages,
+            vec![create_subscriber_msg(sub_a.clone(), &bob, 90).unwrap()]
+        );
+
+        // Only the stake deposited after the emergency redeem moves with the redelegation
+        let result = delegate(&mut deps, mock_env("alice", &[]), carol.clone()).unwrap();
+        assert_eq!(
+            result.messages,
+            vec![
+                create_subscriber_msg(sub_a.clone(), &alice, 0).unwrap(),
+                create_subscriber_msg(sub_a.clone(), &carol, 70).unwrap(),
+                create_subscriber_msg(sub_a, &bob, 50).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_voting_power_checkpoints() {
+        let sub_a = SecretContract {
+            address: HumanAddr("sub_a".to_string()),
+            contract_hash: "".to_string(),
+        };
+        let alice = HumanAddr("alice".to_string());
+        let bob = HumanAddr("bob".to_string());
+
+        let (init_result, mut deps) = init_helper(Some(vec![sub_a]));
+        assert!(init_result.is_ok());
+
+        deposit_helper(&mut deps, "alice".into(), 100);
+        deposit_helper(&mut deps, "bob".into(), 50);
+        let deposit_height = mock_env("alice", &[]).block.height;
+
+        let mut env = mock_env("alice", &[]);
+        env.block.height = deposit_height + 10;
+        delegate(&mut deps, env, bob.clone()).unwrap();
+
+        // Emergency redeems also move power away from the delegate, but only send the refund
+        let mut env = mock_env("alice", &[]);
+        env.block.height = deposit_height + 20;
+        let result = emergency_redeem(&mut deps, env).unwrap();
+        assert_eq!(result.messages.len(), 1);
+
+        for (voter, block, expected) in vec![
+            (&alice, deposit_height, 100),
+            (&alice, deposit_height + 10, 0),
+            (&bob, deposit_height, 50),
+            (&bob, deposit_height + 10, 150),
+            (&bob, deposit_height + 20, 50),
+        ] {
+            assert_eq!(
+                read_voting_power_checkpoint(&deps.storage, voter, block).unwrap(),
+                expected
+            );
+        }
+
+        // Deposit checkpoints are unaffected by delegation
+        assert_eq!(
+            read_user_checkpoint(&deps.storage, &alice, deposit_height + 10).unwrap(),
+            100
+        );
+        assert_eq!(
+            read_user_checkpoint(&deps.storage, &alice, deposit_height + 20).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_large_allocations() {
+        let (init_result, mut deps) = init_helper(None);
+        assert!(init_result.is_ok());
+
+        // 18 decimals LP tokens would overflow the intermediate product of the old calculation
+        let supply = 3_000_000 * REWARD_SCALE;
+        deposit_helper(&mut deps, "user".into(), supply);
+
+        let reward_pool = update_rewards(&mut deps, 10_000_000 * REWARD_SCALE + 1).unwrap();
+        assert_eq!(reward_pool.acc_reward_per_share, 3_333_333_333_333_333_333);
+        // The rounding dust is carried over to the next allocation
+        assert_eq!(reward_pool.residue, 1_000_001);
+
+        let result = redeem_helper(&mut deps, "user".into(), supply);
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        assert_eq!(
+            result.messages[0],
+            secret_toolkit::snip20::transfer_msg(
+                HumanAddr("user".to_string()),
+                Uint128(10_000_000 * REWARD_SCALE - 1_000_000),
+                None,
+                RESPONSE_BLOCK_SIZE,
+                config.reward_token.contract_hash,
+                config.reward_token.address,
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reward_blocks() {
+        let mut msg = init_msg(None);
+        msg.reward_start_block = Some(300);
+        let mut deps = mock_dependencies(20, &[]);
+        let err = init(&mut deps, mock_env("admin", &[]), msg).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err(
+                "reward end block must be after the start block: start=300, end=200"
+            )
+        );
+
+        let (init_result, mut deps) = init_helper(None);
+        init_result.unwrap();
+        let set_blocks =
+            |start_block, end_block, remove_end_block| LPStakingHandleMsg::SetRewardBlocks {
+                start_block,
+                end_block,
+                remove_end_block,
+            };
+
+        let err = handle(
+            &mut deps,
+            mock_env("admin", &[]),
+            set_blocks(Some(200), None, false),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err(
+                "reward end block must be after the start block: start=200, end=200"
+            )
+        );
+        let err = handle(
+            &mut deps,
+            mock_env("admin", &[]),
+            set_blocks(None, Some(300), true),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("can't set and remove the reward end block at the same time")
+        );
+
+        // Without an end block, rewards keep accruing
+        handle(
+            &mut deps,
+            mock_env("admin", &[]),
+            set_blocks(Some(200), None, true),
+        )
+        .unwrap();
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        assert_eq!(config.reward_start_block, 200);
+        assert_eq!(config.reward_end_block, None);
+    }
 }
@@ -1,7 +1,8 @@
 use crate::constants::CONFIG_KEY;
 use crate::state::Config;
 use cosmwasm_std::{
-    to_binary, Api, Extern, Querier, QueryRequest, StdError, StdResult, Storage, WasmQuery,
+    to_binary, Api, Extern, HumanAddr, Querier, QueryRequest, StdError, StdResult, Storage,
+    WasmQuery,
 };
 use scrt_finance::master_msg::{MasterQueryAnswer, MasterQueryMsg};
 use secret_toolkit::storage::TypedStore;
@@ -9,10 +10,10 @@ use secret_toolkit::storage::TypedStore;
 pub fn query_pending<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     block: u64,
-) -> StdResult<u128> {
+) -> StdResult<Vec<(HumanAddr, u128)>> {
     let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
 
-    let mut total_amount = 0;
+    let mut amounts = vec![];
     for rs in config.reward_sources {
         let response = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
             callback_code_hash: rs.contract_hash,
@@ -23,7 +24,7 @@ pub fn query_pending<S: Storage, A: Api, Q: Querier>(
             })?,
         }))?;
 
-        total_amount += match response {
+        let amount = match response {
             MasterQueryAnswer::Pending { amount } => amount.u128(),
             _ => {
                 return Err(StdError::generic_err(format!(
@@ -31,8 +32,9 @@ pub fn query_pending<S: Storage, A: Api, Q: Querier>(
                     rs.address
                 )));
             }
-        }
+        };
+        amounts.push((rs.address, amount));
     }
 
-    Ok(total_amount)
+    Ok(amounts)
 }
@@ -1,5 +1,11 @@
-use cosmwasm_std::HumanAddr;
+use crate::constants::{
+    DELEGATED_POWER_KEY, DELEGATES_KEY, REFERRALS_KEY, REFERRERS_KEY, TOTAL_CHECKPOINTS_KEY,
+    USER_CHECKPOINTS_KEY, VOTING_POWER_CHECKPOINTS_KEY,
+};
+use cosmwasm_std::{CanonicalAddr, HumanAddr, ReadonlyStorage, StdResult, Storage};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
 use scrt_finance::types::SecretContract;
+use secret_toolkit::storage::{AppendStore, AppendStoreMut, TypedStore, TypedStoreMut};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
@@ -12,4 +18,215 @@ pub struct Config {
     pub prng_seed: Vec<u8>,
     pub is_stopped: bool,
     pub own_addr: HumanAddr,
+    pub reward_start_block: u64,
+    pub reward_end_block: Option<u64>,
+    pub referral_share: u16, // In basis points (X/10000)
+}
+
+impl Config {
+    pub fn is_rewarding(&self, block: u64) -> bool {
+        block >= self.reward_start_block && self.reward_end_block.map_or(true, |end| block < end)
+    }
+
+    pub fn has_ended(&self, block: u64) -> bool {
+        self.reward_end_block.map_or(false, |end| block >= end)
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
+pub struct ReferralInfo {
+    pub referees: u64,
+    pub referred_stake: u128,
+    pub rewards_earned: u128,
+}
+
+// Referrers are stored in their canonical form, so only valid addresses can be referrers
+
+pub fn read_referrer<S: ReadonlyStorage>(
+    storage: &S,
+    user: &HumanAddr,
+) -> StdResult<Option<CanonicalAddr>> {
+    let referrers_store = ReadonlyPrefixedStorage::new(REFERRERS_KEY, storage);
+    TypedStore::attach(&referrers_store).may_load(user.0.as_bytes())
+}
+
+pub fn store_referrer<S: Storage>(
+    storage: &mut S,
+    user: &HumanAddr,
+    referrer: &CanonicalAddr,
+) -> StdResult<()> {
+    let mut referrers_store = PrefixedStorage::new(REFERRERS_KEY, storage);
+    TypedStoreMut::attach(&mut referrers_store).store(user.0.as_bytes(), referrer)
+}
+
+pub fn read_referral_info<S: ReadonlyStorage>(
+    storage: &S,
+    referrer: &HumanAddr,
+) -> StdResult<ReferralInfo> {
+    let referrals_store = ReadonlyPrefixedStorage::new(REFERRALS_KEY, storage);
+    Ok(TypedStore::attach(&referrals_store)
+        .may_load(referrer.0.as_bytes())?
+        .unwrap_or_default())
+}
+
+pub fn store_referral_info<S: Storage>(
+    storage: &mut S,
+    referrer: &HumanAddr,
+    info: &ReferralInfo,
+) -> StdResult<()> {
+    let mut referrals_store = PrefixedStorage::new(REFERRALS_KEY, storage);
+    TypedStoreMut::attach(&mut referrals_store).store(referrer.0.as_bytes(), info)
+}
+
+// Delegation is not transitive: power delegated to an address can't be delegated onwards
+
+pub fn read_delegate<S: ReadonlyStorage>(
+    storage: &S,
+    delegator: &HumanAddr,
+) -> StdResult<Option<HumanAddr>> {
+    let delegates_store = ReadonlyPrefixedStorage::new(DELEGATES_KEY, storage);
+    TypedStore::attach(&delegates_store).may_load(delegator.0.as_bytes())
+}
+
+pub fn store_delegate<S: Storage>(
+    storage: &mut S,
+    delegator: &HumanAddr,
+    delegate: Option<&HumanAddr>,
+) -> StdResult<()> {
+    let mut delegates_store = PrefixedStorage::new(DELEGATES_KEY, storage);
+    let mut delegates_store = TypedStoreMut::<HumanAddr, _>::attach(&mut delegates_store);
+    match delegate {
+        Some(delegate) => delegates_store.store(delegator.0.as_bytes(), delegate),
+        None => {
+            delegates_store.remove(delegator.0.as_bytes());
+            Ok(())
+        }
+    }
+}
+
+pub fn read_delegated_power<S: ReadonlyStorage>(
+    storage: &S,
+    delegate: &HumanAddr,
+) -> StdResult<u128> {
+    let power_store = ReadonlyPrefixedStorage::new(DELEGATED_POWER_KEY, storage);
+    Ok(TypedStore::attach(&power_store)
+        .may_load(delegate.0.as_bytes())?
+        .unwrap_or(0))
+}
+
+pub fn store_delegated_power<S: Storage>(
+    storage: &mut S,
+    delegate: &HumanAddr,
+    power: u128,
+) -> StdResult<()> {
+    let mut power_store = PrefixedStorage::new(DELEGATED_POWER_KEY, storage);
+    TypedStoreMut::attach(&mut power_store).store(delegate.0.as_bytes(), &power)
+}
+
+// Checkpoints record the locked amount at the end of every block in which it changed
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct Checkpoint {
+    pub block: u64,
+    pub amount: u128,
+}
+
+pub fn write_user_checkpoint<S: Storage>(
+    storage: &mut S,
+    user: &HumanAddr,
+    block: u64,
+    amount: u128,
+) -> StdResult<()> {
+    let mut store =
+        PrefixedStorage::multilevel(&[USER_CHECKPOINTS_KEY, user.0.as_bytes()], storage);
+    write_checkpoint(&mut store, block, amount)
+}
+
+pub fn write_total_checkpoint<S: Storage>(
+    storage: &mut S,
+    block: u64,
+    amount: u128,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(TOTAL_CHECKPOINTS_KEY, storage);
+    write_checkpoint(&mut store, block, amount)
+}
+
+pub fn read_user_checkpoint<S: ReadonlyStorage>(
+    storage: &S,
+    user: &HumanAddr,
+    block: u64,
+) -> StdResult<u128> {
+    let store =
+        ReadonlyPrefixedStorage::multilevel(&[USER_CHECKPOINTS_KEY, user.0.as_bytes()], storage);
+    read_checkpoint(&store, block)
+}
+
+pub fn read_total_checkpoint<S: ReadonlyStorage>(storage: &S, block: u64) -> StdResult<u128> {
+    let store = ReadonlyPrefixedStorage::new(TOTAL_CHECKPOINTS_KEY, storage);
+    read_checkpoint(&store, block)
+}
+
+// Voting power checkpoints follow delegation, so they always match the live voting power
+
+pub fn write_voting_power_checkpoint<S: Storage>(
+    storage: &mut S,
+    voter: &HumanAddr,
+    block: u64,
+    amount: u128,
+) -> StdResult<()> {
+    let mut store =
+        PrefixedStorage::multilevel(&[VOTING_POWER_CHECKPOINTS_KEY, voter.0.as_bytes()], storage);
+    write_checkpoint(&mut store, block, amount)
+}
+
+pub fn read_voting_power_checkpoint<S: ReadonlyStorage>(
+    storage: &S,
+    voter: &HumanAddr,
+    block: u64,
+) -> StdResult<u128> {
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[VOTING_POWER_CHECKPOINTS_KEY, voter.0.as_bytes()],
+        storage,
+    );
+    read_checkpoint(&store, block)
+}
+
+fn write_checkpoint<S: Storage>(storage: &mut S, block: u64, amount: u128) -> StdResult<()> {
+    let mut store = AppendStoreMut::attach_or_create(storage)?;
+    let checkpoint = Checkpoint { block, amount };
+
+    // Multiple changes in the same block only keep the latest amount
+    if let Some(last_pos) = store.len().checked_sub(1) {
+        let last: Checkpoint = store.get_at(last_pos)?;
+        if last.block == block {
+            return store.set_at(last_pos, &checkpoint);
+        }
+    }
+
+    store.push(&checkpoint)
+}
+
+fn read_checkpoint<S: ReadonlyStorage>(storage: &S, block: u64) -> StdResult<u128> {
+    let store = if let Some(result) = AppendStore::<Checkpoint, _>::attach(storage) {
+        result?
+    } else {
+        return Ok(0);
+    };
+
+    // Binary search for the first checkpoint that was written after `block`
+    let (mut low, mut high) = (0, store.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if store.get_at(mid)?.block <= block {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    if low == 0 {
+        Ok(0)
+    } else {
+        Ok(store.get_at(low - 1)?.amount)
+    }
 }
@@ -12,6 +12,7 @@ use rand_chacha::ChaChaRng;
 use rand_core::{RngCore, SeedableRng};
 
 pub const CHALLENGE_SIZE: usize = 32;
+pub const CHALLENGE_TTL: u64 = 600; // Seconds a new poll has to register itself
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct Challenge(pub String);
@@ -23,12 +24,13 @@ impl Challenge {
         ct_slice_compare(&mine_hashed, hashed_pw)
     }
 
-    pub fn new(env: &Env, seed: &[u8]) -> Self {
-        // 16 here represents the lengths in bytes of the block height and time.
-        let entropy_len = 16 + env.message.sender.len();
+    pub fn new(env: &Env, seed: &[u8], poll_id: u64) -> Self {
+        // 24 here represents the lengths in bytes of the block height, time and poll id.
+        let entropy_len = 24 + env.message.sender.len();
         let mut rng_entropy = Vec::with_capacity(entropy_len);
         rng_entropy.extend_from_slice(&env.block.height.to_be_bytes());
         rng_entropy.extend_from_slice(&env.block.time.to_be_bytes());
+        rng_entropy.extend_from_slice(&poll_id.to_be_bytes());
         rng_entropy.extend_from_slice(&env.message.sender.0.as_bytes());
 
         let mut rng = Prng::new(seed, &rng_entropy);
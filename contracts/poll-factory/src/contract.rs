@@ -1,17 +1,23 @@
-use crate::challenge::{sha_256, Challenge};
-use crate::msg::{InitMsg, QueryAnswer, QueryMsg, ResponseStatus};
+use crate::challenge::{sha_256, Challenge, CHALLENGE_TTL};
+use crate::msg::{InitMsg, NamedTemplate, QueryAnswer, QueryMsg, ResponseStatus};
 use crate::state::{
-    ActivePoll, Config, ACTIVE_POLLS_KEY, ADMIN_KEY, CONFIG_KEY, CURRENT_CHALLENGE_KEY,
-    DEFAULT_POLL_CONFIG_KEY,
+    push_poll_record, read_last_poll_time, read_pending_poll, read_poll_id, read_poll_record,
+    read_poll_records, read_template, read_templates, read_voter_polls, set_poll_address,
+    store_last_poll_time, store_pending_poll, store_template, store_voter_polls,
+    update_poll_status, ActivePoll, Config, DepositStatus, PendingPoll, PollDeposit, PollExecution,
+    PollRecord, PollStatus, ACTIVE_POLLS_KEY, ADMIN_KEY, CONFIG_KEY, DEFAULT_POLL_CONFIG_KEY,
+    DEPOSITS_KEY, DEPOSIT_SETTLEMENT_TIMEOUT, EXECUTIONS_KEY,
 };
 use cosmwasm_std::{
-    log, to_binary, Api, Binary, CosmosMsg, Env, Extern, HandleResponse, HumanAddr, InitResponse,
-    Querier, StdError, StdResult, Storage, Uint128, WasmMsg,
+    from_binary, log, to_binary, Api, Binary, CosmosMsg, Env, Extern, HandleResponse, HumanAddr,
+    InitResponse, LogAttribute, Querier, StdError, StdResult, Storage, Uint128, WasmMsg,
 };
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
 use scrt_finance::secret_vote_types::PollFactoryHandleMsg::RegisterForUpdates;
 use scrt_finance::secret_vote_types::{
-    InitHook, PollConfig, PollContract, PollFactoryHandleMsg, PollHandleMsg, PollInitMsg,
-    PollMetadata, RevealCommittee,
+    log_actions, log_keys, DepositConfig, ExecutionRules, InitHook, PollConfig, PollContract,
+    PollFactoryHandleMsg, PollFactoryReceiveMsg, PollHandleMsg, PollInitMsg, PollLimits,
+    PollMetadata, PollOutcome, PollTemplate, RevealCommittee, WeightedPool,
 };
 use scrt_finance::types::SecretContract;
 use secret_toolkit::snip20;
@@ -28,6 +34,17 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     TypedStoreMut::attach(&mut deps.storage)
         .store(DEFAULT_POLL_CONFIG_KEY, &msg.default_poll_config)?;
 
+    let mut messages = vec![];
+    if let Some(deposit) = &msg.deposit {
+        messages.push(snip20::register_receive_msg(
+            env.contract_code_hash,
+            None,
+            1, // This is public data, no need to pad
+            deposit.token.contract_hash.clone(),
+            deposit.token.address.clone(),
+        )?);
+    }
+
     let prng_seed_hashed = sha_256(&msg.prng_seed.0);
     TypedStoreMut::attach(&mut deps.storage).store(
         CONFIG_KEY,
@@ -41,10 +58,18 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
             prng_seed: prng_seed_hashed,
             min_staked: msg.min_staked.u128(),
             reveal_com: msg.reveal_com,
+            deposit: msg.deposit,
+            limits: msg.limits.unwrap_or_default(),
+            guardian: msg.guardian,
+            extra_pools: msg.extra_pools.unwrap_or_default(),
+            execution_rules: msg.execution_rules,
         },
     )?;
 
-    Ok(InitResponse::default())
+    Ok(InitResponse {
+        messages,
+        log: vec![],
+    })
 }
 
 pub fn handle<S: Storage, A: Api, Q: Querier>(
@@ -55,17 +80,36 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     match msg {
         PollFactoryHandleMsg::NewPoll {
             poll_metadata,
+            template,
             poll_config,
             poll_choices,
             pool_viewing_key,
-        } => new_poll(
-            deps,
-            env,
-            poll_metadata,
-            poll_config.unwrap_or(TypedStore::attach(&deps.storage).load(DEFAULT_POLL_CONFIG_KEY)?),
-            poll_choices,
-            pool_viewing_key,
-        ),
+            execute_msgs,
+        } => {
+            let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+            if deposit_required(&config) {
+                return Err(StdError::generic_err(
+                    "a deposit is required to create a poll, send it with the deposit token",
+                ));
+            }
+
+            let author = env.message.sender.clone();
+            new_poll(
+                deps,
+                env,
+                author,
+                poll_metadata,
+                template,
+                poll_config,
+                poll_choices,
+                pool_viewing_key,
+                execute_msgs.unwrap_or_default(),
+                None,
+            )
+        }
+        PollFactoryHandleMsg::Receive {
+            from, amount, msg, ..
+        } => receive(deps, env, from, amount.u128(), msg),
         PollFactoryHandleMsg::UpdateVotingPower { voter, new_power } => {
             update_voting_power(deps, env, voter, new_power)
         }
@@ -73,17 +117,50 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             duration,
             quorum,
             min_threshold,
-        } => update_default_poll_config(deps, env, duration, quorum, min_threshold),
+            quorum_inclusive,
+            threshold_inclusive,
+            reveal_period,
+            voting_delay,
+            snapshot_voting,
+        } => update_default_poll_config(
+            deps,
+            env,
+            duration,
+            quorum,
+            min_threshold,
+            quorum_inclusive,
+            threshold_inclusive,
+            reveal_period,
+            voting_delay,
+            snapshot_voting,
+        ),
         PollFactoryHandleMsg::RegisterForUpdates {
             challenge,
+            poll_id,
             end_time,
-        } => register_for_updates(deps, env, Challenge(challenge), end_time),
+        } => register_for_updates(deps, env, Challenge(challenge), poll_id, end_time),
+        PollFactoryHandleMsg::RegisterVoter { voter } => register_voter(deps, env, voter),
+        PollFactoryHandleMsg::ExecutePoll {} => execute_poll(deps, env),
+        PollFactoryHandleMsg::PollFinalized { outcome } => poll_finalized(deps, env, outcome),
+        PollFactoryHandleMsg::PollCancelled {} => poll_cancelled(deps, env),
+        PollFactoryHandleMsg::CancelPoll { poll } => cancel_poll(deps, env, poll),
+        PollFactoryHandleMsg::SettleDeposit { poll } => settle_unfinalized_deposit(deps, env, poll),
         PollFactoryHandleMsg::ChangeAdmin { new_admin } => change_admin(deps, env, new_admin),
+        PollFactoryHandleMsg::SetTemplate { name, template } => {
+            set_template(deps, env, name, Some(template))
+        }
+        PollFactoryHandleMsg::RemoveTemplate { name } => set_template(deps, env, name, None),
+        PollFactoryHandleMsg::RemoveGuardian {} => remove_guardian(deps, env),
         PollFactoryHandleMsg::UpdateConfig {
             new_poll_code,
             new_staking_pool,
             new_min_stake_amount,
             new_reveal_com,
+            new_deposit,
+            new_limits,
+            new_guardian,
+            new_extra_pools,
+            new_execution_rules,
         } => update_config(
             deps,
             env,
@@ -91,6 +168,11 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             new_staking_pool,
             new_min_stake_amount,
             new_reveal_com,
+            new_deposit,
+            new_limits,
+            new_guardian,
+            new_extra_pools,
+            new_execution_rules,
         ),
     }
 }
@@ -103,72 +185,228 @@ pub fn query<S: Storage, A: Api, Q: Querier>(
         QueryMsg::ActivePolls { current_time } => query_active_polls(deps, current_time),
         QueryMsg::DefaultPollConfig {} => query_default_poll_config(deps),
         QueryMsg::StakingPool {} => query_staking_pool(deps),
+        QueryMsg::ExtraPools {} => query_extra_pools(deps),
         QueryMsg::PollCode {} => query_poll_code(deps),
         QueryMsg::Admin {} => query_admin(deps),
         QueryMsg::RevealCommittee {} => query_reveal_com(deps),
         QueryMsg::MinimumStake {} => query_min_stake(deps),
+        QueryMsg::PollLimits {} => query_poll_limits(deps),
+        QueryMsg::Guardian {} => query_guardian(deps),
+        QueryMsg::ExecutionRules {} => query_execution_rules(deps),
+        QueryMsg::Templates {} => query_templates(deps),
+        QueryMsg::PollExecution { poll } => query_poll_execution(deps, poll),
+        QueryMsg::Polls {
+            status,
+            page,
+            page_size,
+        } => query_polls(deps, status, page, page_size),
+        QueryMsg::PollById { id } => query_poll_by_id(deps, id),
+        QueryMsg::ProposalDeposit {} => query_proposal_deposit(deps),
+        QueryMsg::PollDeposit { poll } => query_poll_deposit(deps, poll),
     }
 }
 
 // Handle function
 
+fn receive<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    amount: u128,
+    msg: Binary,
+) -> StdResult<HandleResponse> {
+    let msg: PollFactoryReceiveMsg = from_binary(&msg)?;
+
+    match msg {
+        PollFactoryReceiveMsg::NewPoll {
+            poll_metadata,
+            template,
+            poll_config,
+            poll_choices,
+            pool_viewing_key,
+            execute_msgs,
+        } => {
+            let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+            let deposit_config = config
+                .deposit
+                .ok_or_else(|| StdError::generic_err("this factory doesn't accept deposits"))?;
+            if env.message.sender != deposit_config.token.address {
+                return Err(StdError::generic_err(
+                    "deposits are only accepted in the deposit token",
+                ));
+            }
+            if amount < deposit_config.amount.u128() {
+                return Err(StdError::generic_err(format!(
+                    "insufficient deposit. Minimum deposit to create a poll is {}",
+                    deposit_config.amount
+                )));
+            }
+
+            let deposit = PollDeposit {
+                depositor: from.clone(),
+                token: deposit_config.token,
+                amount: Uint128(amount),
+                status: DepositStatus::Held,
+            };
+            new_poll(
+                deps,
+                env,
+                from,
+                poll_metadata,
+                template,
+                poll_config,
+                poll_choices,
+                pool_viewing_key,
+                execute_msgs.unwrap_or_default(),
+                Some(deposit),
+            )
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn new_poll<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    author: HumanAddr,
     poll_metadata: PollMetadata,
-    poll_config: PollConfig,
+    template: Option<String>,
+    poll_config: Option<PollConfig>,
     poll_choices: Vec<String>,
     pool_vk: String,
+    execute_msgs: Vec<CosmosMsg>,
+    deposit: Option<PollDeposit>,
 ) -> StdResult<HandleResponse> {
     let mut config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    let (poll_config, min_staked) = match template {
+        Some(name) => {
+            let template = read_template(&deps.storage, &name)?;
+            let poll_config =
+                apply_template(&template, poll_config, &poll_metadata, &poll_choices)?;
+            let min_staked = template.min_staked.map_or(config.min_staked, |m| m.u128());
+            (poll_config, min_staked)
+        }
+        None => {
+            let default_config: PollConfig =
+                TypedStore::attach(&deps.storage).load(DEFAULT_POLL_CONFIG_KEY)?;
+            let poll_config = match poll_config {
+                Some(poll_config) => {
+                    enforce_config_bounds(&default_config, &poll_config, "the default config")?;
+                    poll_config
+                }
+                None => default_config,
+            };
+            (poll_config, config.min_staked)
+        }
+    };
+
+    enforce_execution_rules(&config, &poll_config, &execute_msgs, &env.contract.address)?;
+    enforce_poll_timing(&config.limits, &poll_config)?;
+    let too_long = || StdError::generic_err("voting delay and duration are too long");
+    let start_time = env
+        .block
+        .time
+        .checked_add(poll_config.voting_delay.unwrap_or(0))
+        .ok_or_else(too_long)?;
+    let end_time = start_time
+        .checked_add(poll_config.duration)
+        .ok_or_else(too_long)?;
+    // Mirrors the deadline the poll sets for its reveal committee
+    let finalize_by = end_time
+        .checked_add(poll_config.reveal_period.unwrap_or(0))
+        .and_then(|time| time.checked_add(config.reveal_com.timeout.unwrap_or(0)))
+        .ok_or_else(|| StdError::generic_err("reveal period and committee timeout are too long"))?;
+    if let (Some(_), Some(deposit_config)) = (&deposit, &config.deposit) {
+        if poll_config.quorum < deposit_config.min_quorum {
+            return Err(StdError::generic_err(format!(
+                "polls that take a deposit need a quorum of at least {}",
+                deposit_config.min_quorum
+            )));
+        }
+    }
 
-    // Verify minimum staked amount
+    // Verify minimum staked amount. Only SEFI staked in the staking pool counts, extra pools add
+    // voting power but don't qualify an author on their own
     let voting_power = snip20::balance_query(
         &deps.querier,
-        env.message.sender.clone(),
+        author.clone(),
         pool_vk,
         256,
         config.staking_pool.contract_hash.clone(),
         config.staking_pool.address.clone(),
     )?;
-    if voting_power.amount.u128() < config.min_staked {
+    if voting_power.amount.u128() < min_staked {
         return Err(StdError::generic_err(format!(
             "insufficient staked amount. Minimum staked SEFI to create a poll is {}",
-            config.min_staked / 1_000_000
+            min_staked / 1_000_000
         )));
     }
 
-    let key = Challenge::new(&env, &config.prng_seed);
-    TypedStoreMut::attach(&mut deps.storage).store(CURRENT_CHALLENGE_KEY, &key)?;
+    enforce_poll_limits(deps, &config.limits, &author, env.block.time)?;
+    store_last_poll_time(&mut deps.storage, &author, env.block.time)?;
+
+    // Each poll gets its own challenge, so polls created in the same block don't clobber each other
+    let poll_id = config.id_counter as u64;
+    let key = Challenge::new(&env, &config.prng_seed, poll_id);
+    let label = format!("secret-poll-{}-{}", poll_id, &key.to_string()[0..8]);
+
+    // Ids are assigned here, so the registry stays in order whichever poll registers first
+    push_poll_record(
+        &mut deps.storage,
+        &PollRecord {
+            id: poll_id,
+            address: HumanAddr::default(), // Known once the poll registers itself
+            title: poll_metadata.title.clone(),
+            vote_type: poll_metadata.vote_type.clone(),
+            author: author.clone(),
+            author_alias: poll_metadata.author_alias.clone(),
+            start_time,
+            end_time,
+            finalize_by,
+            status: PollStatus::Active,
+            code_hash: config.poll_contract.code_hash.clone(),
+            label: label.clone(),
+        },
+    )?;
+    store_pending_poll(
+        &mut deps.storage,
+        poll_id,
+        Some(&PendingPoll {
+            challenge: key.clone(),
+            expires_at: env.block.time + CHALLENGE_TTL,
+            execute_msgs,
+            deposit,
+        }),
+    )?;
 
+    let author_log = author.clone();
     let init_msg = PollInitMsg {
         metadata: PollMetadata {
             title: poll_metadata.title,
             description: poll_metadata.description,
             vote_type: poll_metadata.vote_type,
-            author_addr: Some(env.message.sender),
+            author_addr: Some(author),
             author_alias: poll_metadata.author_alias,
         },
         config: poll_config.clone(),
         reveal_com: config.reveal_com.clone(),
         choices: poll_choices,
         staking_pool: config.staking_pool.clone(),
+        extra_pools: config.extra_pools.clone(),
         init_hook: Some(InitHook {
             contract_addr: env.contract.address,
             code_hash: env.contract_code_hash,
             msg: to_binary(&RegisterForUpdates {
                 challenge: key.to_string(),
-                end_time: env.block.time + poll_config.duration, // If this overflows, we have bigger problems than this :)
+                poll_id,
+                end_time,
             })?,
         }),
+        prng_seed: Binary(
+            sha_256(&[&config.prng_seed[..], &poll_id.to_be_bytes()].concat()).to_vec(),
+        ),
     };
 
-    let label = format!(
-        "secret-poll-{}-{}",
-        config.id_counter,
-        &key.to_string()[0..8],
-    );
-
     config.id_counter += 1;
     TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
 
@@ -178,9 +416,14 @@ fn new_poll<S: Storage, A: Api, Q: Querier>(
             callback_code_hash: config.poll_contract.code_hash,
             msg: to_binary(&init_msg)?,
             send: vec![],
-            label,
+            label: label.clone(),
         })],
-        log: vec![],
+        log: vec![
+            log(log_keys::ACTION, log_actions::NEW_POLL),
+            log(log_keys::POLL_ID, poll_id),
+            log(log_keys::AUTHOR, author_log),
+            log(log_keys::LABEL, label),
+        ],
         data: None,
     })
 }
@@ -189,34 +432,303 @@ fn register_for_updates<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     response: Challenge,
+    poll_id: u64,
     end_time: u64,
 ) -> StdResult<HandleResponse> {
-    let challenge: Challenge = TypedStore::attach(&deps.storage).load(CURRENT_CHALLENGE_KEY)?;
-    if !response.check_challenge(&challenge.to_hashed()) {
+    let pending = read_pending_poll(&deps.storage, poll_id)?;
+    if !response.check_challenge(&pending.challenge.to_hashed()) {
         return Err(StdError::generic_err("challenge did not match. This function can be called only as a callback from a new poll contract"));
-    } else {
-        TypedStoreMut::<Challenge, S>::attach(&mut deps.storage).remove(CURRENT_CHALLENGE_KEY);
     }
+    if env.block.time > pending.expires_at {
+        return Err(StdError::generic_err("challenge has expired"));
+    }
+    store_pending_poll(&mut deps.storage, poll_id, None)?;
 
-    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
-    let mut active_polls_store = TypedStoreMut::<Vec<ActivePoll>, S>::attach(&mut deps.storage);
-    let mut active_polls = active_polls_store
-        .load(ACTIVE_POLLS_KEY)
-        .unwrap_or_default();
+    // Polls stay in the registry after they end
+    let record = set_poll_address(&mut deps.storage, poll_id, &env.message.sender)?;
+
+    let mut active_polls = remove_inactive_polls(deps, &env)?;
     active_polls.push(ActivePoll {
         address: env.message.sender.clone(),
-        hash: config.poll_contract.code_hash,
+        hash: record.code_hash.clone(),
+        start_time: record.start_time,
         end_time,
+        author: record.author.clone(),
     });
-    active_polls_store.store(ACTIVE_POLLS_KEY, &active_polls)?;
+    TypedStoreMut::attach(&mut deps.storage).store(ACTIVE_POLLS_KEY, &active_polls)?;
+
+    if let Some(deposit) = pending.deposit {
+        let mut deposits_store = PrefixedStorage::new(DEPOSITS_KEY, &mut deps.storage);
+        TypedStoreMut::attach(&mut deposits_store)
+            .store(env.message.sender.0.as_bytes(), &deposit)?;
+    }
+
+    let execute_msgs = pending.execute_msgs;
+    if !execute_msgs.is_empty() {
+        let mut executions_store = PrefixedStorage::new(EXECUTIONS_KEY, &mut deps.storage);
+        TypedStoreMut::attach(&mut executions_store).store(
+            env.message.sender.0.as_bytes(),
+            &PollExecution {
+                messages: execute_msgs,
+                executed: false,
+            },
+        )?;
+    }
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: poll_log(log_actions::REGISTER_POLL, &record),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn register_voter<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    voter: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let poll = get_active_polls(deps, env.block.time)?
+        .into_iter()
+        .find(|p| p.address == env.message.sender)
+        .ok_or_else(StdError::unauthorized)?;
+
+    let mut voter_polls = read_voter_polls(&deps.storage, &voter)?;
+    voter_polls.retain(|p| p.end_time >= env.block.time);
+    if !voter_polls.iter().any(|p| p.address == poll.address) {
+        voter_polls.push(poll);
+    }
+    store_voter_polls(&mut deps.storage, &voter, &voter_polls)?;
 
     Ok(HandleResponse {
         messages: vec![],
-        log: vec![log("new_poll", env.message.sender)],
+        log: vec![],
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn execute_poll<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let id =
+        read_poll_id(&deps.storage, &env.message.sender)?.ok_or_else(StdError::unauthorized)?;
+    let record = read_poll_record(&deps.storage, id)?;
+    if record.status != PollStatus::Passed {
+        return Err(StdError::generic_err("only passed polls can be executed"));
+    }
+
+    let mut executions_store = PrefixedStorage::new(EXECUTIONS_KEY, &mut deps.storage);
+    let mut executions_store = TypedStoreMut::<PollExecution, _>::attach(&mut executions_store);
+    let mut execution = executions_store
+        .may_load(env.message.sender.0.as_bytes())?
+        .ok_or_else(|| StdError::generic_err("this poll has no messages to execute"))?;
+
+    if execution.executed {
+        return Err(StdError::generic_err("poll was already executed"));
+    }
+
+    execution.executed = true;
+    executions_store.store(env.message.sender.0.as_bytes(), &execution)?;
+
+    Ok(HandleResponse {
+        messages: execution.messages,
+        log: poll_log(log_actions::EXECUTE, &record),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn poll_finalized<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    outcome: PollOutcome,
+) -> StdResult<HandleResponse> {
+    let id =
+        read_poll_id(&deps.storage, &env.message.sender)?.ok_or_else(StdError::unauthorized)?;
+    let record = read_poll_record(&deps.storage, id)?;
+    if record.status != PollStatus::Active {
+        return Err(StdError::generic_err("poll status was already set"));
+    }
+
+    // Deposits are refunded if the poll reached quorum, whether it passed or not
+    let reached_quorum = outcome != PollOutcome::QuorumNotReached;
+    let record = update_poll_status(&mut deps.storage, &env.message.sender, outcome.into())?;
+    let messages = settle_deposit(deps, &env.message.sender, reached_quorum)?;
+
+    Ok(HandleResponse {
+        messages,
+        log: poll_log(log_actions::FINALIZE, &record),
         data: Some(to_binary(&ResponseStatus::Success)?),
     })
 }
 
+fn poll_cancelled<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let id =
+        read_poll_id(&deps.storage, &env.message.sender)?.ok_or_else(StdError::unauthorized)?;
+
+    // Only the author can cancel a poll directly, and only before any votes were cast. The deposit
+    // is only refunded if that happened before voting ended
+    let record = read_poll_record(&deps.storage, id)?;
+    let refund = env.block.time <= record.end_time;
+    let (messages, record) = cancel_poll_record(deps, &env.message.sender, refund)?;
+
+    Ok(HandleResponse {
+        messages,
+        log: poll_log(log_actions::CANCEL, &record),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn cancel_poll<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    poll: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if config.guardian.as_ref() != Some(&env.message.sender) {
+        return Err(StdError::unauthorized());
+    }
+    read_poll_id(&deps.storage, &poll)?
+        .ok_or_else(|| StdError::generic_err("not a registered poll"))?;
+
+    // Polls that were stopped by the guardian don't get their deposit back
+    let poll_hash = TypedStore::<Vec<ActivePoll>, S>::attach(&deps.storage)
+        .load(ACTIVE_POLLS_KEY)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|p| p.address == poll)
+        .map_or(config.poll_contract.code_hash, |p| p.hash);
+    let (mut messages, record) = cancel_poll_record(deps, &poll, false)?;
+    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: poll.clone(),
+        callback_code_hash: poll_hash,
+        msg: to_binary(&PollHandleMsg::Cancel {})?,
+        send: vec![],
+    }));
+
+    Ok(HandleResponse {
+        messages,
+        log: poll_log(log_actions::CANCEL, &record),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn settle_unfinalized_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    poll: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let id = read_poll_id(&deps.storage, &poll)?
+        .ok_or_else(|| StdError::generic_err("not a registered poll"))?;
+    let record = read_poll_record(&deps.storage, id)?;
+    if record.status != PollStatus::Active {
+        return Err(StdError::generic_err("poll status was already set"));
+    }
+
+    let admin: HumanAddr = TypedStore::attach(&deps.storage).load(ADMIN_KEY)?;
+    let settle_after = if env.message.sender == admin {
+        record.finalize_by
+    } else {
+        record
+            .finalize_by
+            .saturating_add(DEPOSIT_SETTLEMENT_TIMEOUT)
+    };
+    if env.block.time <= settle_after {
+        return Err(StdError::generic_err(
+            "this poll's deposit can't be settled yet",
+        ));
+    }
+
+    // A poll that was never finalized didn't reach a quorum as far as the deposit is concerned. It
+    // stays active, so it can still report its outcome if it's finalized later
+    let messages = settle_deposit(deps, &poll, false)?;
+    if messages.is_empty() {
+        return Err(StdError::generic_err("this poll has no held deposit"));
+    }
+
+    Ok(HandleResponse {
+        messages,
+        log: poll_log(log_actions::SETTLE_DEPOSIT, &record),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn cancel_poll_record<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    poll: &HumanAddr,
+    refund: bool,
+) -> StdResult<(Vec<CosmosMsg>, PollRecord)> {
+    let id = read_poll_id(&deps.storage, poll)?
+        .ok_or_else(|| StdError::generic_err("not a registered poll"))?;
+    let record = read_poll_record(&deps.storage, id)?;
+    if record.status != PollStatus::Active {
+        return Err(StdError::generic_err("poll status was already set"));
+    }
+    let record = update_poll_status(&mut deps.storage, poll, PollStatus::Cancelled)?;
+
+    let mut active_polls_store = TypedStoreMut::<Vec<ActivePoll>, S>::attach(&mut deps.storage);
+    let mut active_polls = active_polls_store
+        .load(ACTIVE_POLLS_KEY)
+        .unwrap_or_default();
+    active_polls.retain(|p| &p.address != poll);
+    active_polls_store.store(ACTIVE_POLLS_KEY, &active_polls)?;
+
+    Ok((settle_deposit(deps, poll, refund)?, record))
+}
+
+fn settle_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    poll: &HumanAddr,
+    refund: bool,
+) -> StdResult<Vec<CosmosMsg>> {
+    let mut messages = vec![];
+    let deposits_store = ReadonlyPrefixedStorage::new(DEPOSITS_KEY, &deps.storage);
+    let deposit: Option<PollDeposit> =
+        TypedStore::attach(&deposits_store).may_load(poll.0.as_bytes())?;
+    // A deposit that was already settled is left alone
+    if let Some(mut deposit) = deposit.filter(|d| d.status == DepositStatus::Held) {
+        let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+        let slash_recipient = config.deposit.and_then(|d| d.slash_recipient);
+        let token = deposit.token.clone();
+        messages.push(if refund {
+            deposit.status = DepositStatus::Refunded;
+            snip20::transfer_msg(
+                deposit.depositor.clone(),
+                deposit.amount,
+                None,
+                256,
+                token.contract_hash,
+                token.address,
+            )?
+        } else if let Some(recipient) = slash_recipient {
+            deposit.status = DepositStatus::Slashed;
+            snip20::transfer_msg(
+                recipient,
+                deposit.amount,
+                None,
+                256,
+                token.contract_hash,
+                token.address,
+            )?
+        } else {
+            deposit.status = DepositStatus::Slashed;
+            snip20::burn_msg(
+                deposit.amount,
+                None,
+                256,
+                token.contract_hash,
+                token.address,
+            )?
+        });
+        let mut deposits_store = PrefixedStorage::new(DEPOSITS_KEY, &mut deps.storage);
+        TypedStoreMut::attach(&mut deposits_store).store(poll.0.as_bytes(), &deposit)?;
+    }
+
+    Ok(messages)
+}
+
 fn update_voting_power<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
@@ -224,18 +736,31 @@ fn update_voting_power<S: Storage, A: Api, Q: Querier>(
     new_power: Uint128,
 ) -> StdResult<HandleResponse> {
     let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
-    if env.message.sender != config.staking_pool.address {
+    let is_pool = env.message.sender == config.staking_pool.address
+        || config
+            .extra_pools
+            .iter()
+            .any(|p| p.pool.address == env.message.sender);
+    if !is_pool {
         return Err(StdError::unauthorized());
     }
 
     let update_msg = to_binary(&PollHandleMsg::UpdateVotingPower {
         voter: voter.clone(),
         new_power,
+        pool: Some(env.message.sender.clone()),
     })?; // This API should be kept if a new poll contract is introduced
 
+    // Ended polls are dropped, so the number of messages is bounded by the voter's active votes
+    let mut voter_polls = read_voter_polls(&deps.storage, &voter)?;
+    voter_polls.retain(|p| p.end_time >= env.block.time);
+    store_voter_polls(&mut deps.storage, &voter, &voter_polls)?;
+
     let mut messages = vec![];
-    let active_polls = remove_inactive_polls(deps, &env)?;
-    for poll in active_polls {
+    for poll in voter_polls
+        .into_iter()
+        .filter(|p| p.start_time <= env.block.time)
+    {
         messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: poll.address,
             callback_code_hash: poll.hash,
@@ -246,7 +771,10 @@ fn update_voting_power<S: Storage, A: Api, Q: Querier>(
 
     Ok(HandleResponse {
         messages,
-        log: vec![log("voting power update", voter.0)],
+        log: vec![
+            log(log_keys::ACTION, log_actions::UPDATE_VOTING_POWER),
+            log(log_keys::VOTER, voter),
+        ],
         data: None,
     })
 }
@@ -257,6 +785,11 @@ fn update_default_poll_config<S: Storage, A: Api, Q: Querier>(
     duration: Option<u64>,
     quorum: Option<u8>,
     min_threshold: Option<u8>,
+    quorum_inclusive: Option<bool>,
+    threshold_inclusive: Option<bool>,
+    reveal_period: Option<u64>,
+    voting_delay: Option<u64>,
+    snapshot_voting: Option<bool>,
 ) -> StdResult<HandleResponse> {
     enforce_admin(deps, &env)?;
 
@@ -275,11 +808,87 @@ fn update_default_poll_config<S: Storage, A: Api, Q: Querier>(
         default_config.min_threshold = new_threshold;
     }
 
+    if let Some(inclusive) = quorum_inclusive {
+        default_config.quorum_inclusive = inclusive;
+    }
+
+    if let Some(inclusive) = threshold_inclusive {
+        default_config.threshold_inclusive = inclusive;
+    }
+
+    if let Some(new_reveal_period) = reveal_period {
+        default_config.reveal_period = if new_reveal_period == 0 {
+            None
+        } else {
+            Some(new_reveal_period)
+        };
+    }
+
+    if let Some(new_voting_delay) = voting_delay {
+        default_config.voting_delay = if new_voting_delay == 0 {
+            None
+        } else {
+            Some(new_voting_delay)
+        };
+    }
+
+    if let Some(snapshot_voting) = snapshot_voting {
+        default_config.snapshot_voting = snapshot_voting;
+    }
+
     poll_config_store.store(DEFAULT_POLL_CONFIG_KEY, &default_config)?;
 
     Ok(HandleResponse {
         messages: vec![],
-        log: vec![],
+        log: vec![log(
+            log_keys::ACTION,
+            log_actions::UPDATE_DEFAULT_POLL_CONFIG,
+        )],
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+fn set_template<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    name: String,
+    template: Option<PollTemplate>,
+) -> StdResult<HandleResponse> {
+    enforce_admin(deps, &env)?;
+
+    if template.is_none() {
+        read_template(&deps.storage, &name)?;
+    }
+    store_template(&mut deps.storage, &name, template.as_ref())?;
+
+    let action = if template.is_some() {
+        log_actions::SET_TEMPLATE
+    } else {
+        log_actions::REMOVE_TEMPLATE
+    };
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![log(log_keys::ACTION, action), log(log_keys::TEMPLATE, name)],
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+// `UpdateConfig` can only replace the guardian, this leaves polls without one
+fn remove_guardian<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    enforce_admin(deps, &env)?;
+
+    let mut config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if config.guardian.take().is_none() {
+        return Err(StdError::generic_err("there is no guardian to remove"));
+    }
+    TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![log(log_keys::ACTION, log_actions::REMOVE_GUARDIAN)],
         data: Some(to_binary(&ResponseStatus::Success)?),
     })
 }
@@ -295,7 +904,7 @@ fn change_admin<S: Storage, A: Api, Q: Querier>(
 
     Ok(HandleResponse {
         messages: vec![],
-        log: vec![],
+        log: vec![log(log_keys::ACTION, log_actions::CHANGE_ADMIN)],
         data: Some(to_binary(&ResponseStatus::Success)?),
     })
 }
@@ -307,6 +916,11 @@ fn update_config<S: Storage, A: Api, Q: Querier>(
     new_staking_pool: Option<SecretContract>,
     new_min_stake_amount: Option<Uint128>,
     new_reveal_com: Option<RevealCommittee>,
+    new_deposit: Option<DepositConfig>,
+    new_limits: Option<PollLimits>,
+    new_guardian: Option<HumanAddr>,
+    new_extra_pools: Option<Vec<WeightedPool>>,
+    new_execution_rules: Option<ExecutionRules>,
 ) -> StdResult<HandleResponse> {
     enforce_admin(deps, &env)?;
 
@@ -329,11 +943,39 @@ fn update_config<S: Storage, A: Api, Q: Querier>(
         config.reveal_com = new_committee;
     }
 
+    let mut messages = vec![];
+    if let Some(new_deposit) = new_deposit {
+        messages.push(snip20::register_receive_msg(
+            env.contract_code_hash,
+            None,
+            1,
+            new_deposit.token.contract_hash.clone(),
+            new_deposit.token.address.clone(),
+        )?);
+        config.deposit = Some(new_deposit);
+    }
+
+    if let Some(new_limits) = new_limits {
+        config.limits = new_limits;
+    }
+
+    if let Some(new_guardian) = new_guardian {
+        config.guardian = Some(new_guardian);
+    }
+
+    if let Some(new_extra_pools) = new_extra_pools {
+        config.extra_pools = new_extra_pools;
+    }
+
+    if let Some(new_execution_rules) = new_execution_rules {
+        config.execution_rules = Some(new_execution_rules);
+    }
+
     config_store.store(CONFIG_KEY, &config)?;
 
     Ok(HandleResponse {
-        messages: vec![],
-        log: vec![],
+        messages,
+        log: vec![log(log_keys::ACTION, log_actions::UPDATE_CONFIG)],
         data: Some(to_binary(&ResponseStatus::Success)?),
     })
 }
@@ -368,6 +1010,14 @@ fn query_staking_pool<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) ->
     })?)
 }
 
+fn query_extra_pools<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+
+    Ok(to_binary(&QueryAnswer::ExtraPools {
+        pools: config.extra_pools,
+    })?)
+}
+
 fn query_poll_code<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
     let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
 
@@ -398,8 +1048,296 @@ fn query_min_stake<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> St
     })?)
 }
 
+fn query_templates<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
+    let templates = read_templates(&deps.storage)?
+        .into_iter()
+        .map(|(name, template)| NamedTemplate { name, template })
+        .collect();
+
+    Ok(to_binary(&QueryAnswer::Templates { templates })?)
+}
+
+fn query_guardian<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+
+    Ok(to_binary(&QueryAnswer::Guardian {
+        address: config.guardian,
+    })?)
+}
+
+fn query_execution_rules<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+
+    Ok(to_binary(&QueryAnswer::ExecutionRules {
+        rules: config.execution_rules,
+    })?)
+}
+
+fn query_poll_limits<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+
+    Ok(to_binary(&QueryAnswer::PollLimits {
+        limits: config.limits,
+    })?)
+}
+
+fn query_poll_execution<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    poll: HumanAddr,
+) -> StdResult<Binary> {
+    let executions_store = ReadonlyPrefixedStorage::new(EXECUTIONS_KEY, &deps.storage);
+    let execution = TypedStore::attach(&executions_store).may_load(poll.0.as_bytes())?;
+
+    Ok(to_binary(&QueryAnswer::PollExecution { execution })?)
+}
+
+fn query_polls<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    status: Option<PollStatus>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<Binary> {
+    let (polls, total) = read_poll_records(&deps.storage, status.as_ref(), page, page_size)?;
+
+    Ok(to_binary(&QueryAnswer::Polls { polls, total })?)
+}
+
+fn query_poll_by_id<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    id: u64,
+) -> StdResult<Binary> {
+    let poll = read_poll_record(&deps.storage, id)?;
+
+    Ok(to_binary(&QueryAnswer::PollById { poll })?)
+}
+
+fn query_proposal_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> StdResult<Binary> {
+    let config: Config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+
+    Ok(to_binary(&QueryAnswer::ProposalDeposit {
+        deposit: config.deposit,
+    })?)
+}
+
+fn query_poll_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    poll: HumanAddr,
+) -> StdResult<Binary> {
+    let deposits_store = ReadonlyPrefixedStorage::new(DEPOSITS_KEY, &deps.storage);
+    let deposit = TypedStore::attach(&deposits_store).may_load(poll.0.as_bytes())?;
+
+    Ok(to_binary(&QueryAnswer::PollDeposit { deposit })?)
+}
+
 // Helper functions
 
+fn enforce_poll_limits<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    limits: &PollLimits,
+    author: &HumanAddr,
+    current_time: u64,
+) -> StdResult<()> {
+    let active_polls = get_active_polls(deps, current_time)?;
+
+    if let Some(max) = limits.max_active_polls {
+        if active_polls.len() as u32 >= max {
+            return Err(StdError::generic_err(format!(
+                "too many active polls. Maximum number of active polls is {}",
+                max
+            )));
+        }
+    }
+
+    if let Some(max) = limits.max_active_polls_per_author {
+        let authored = active_polls.iter().filter(|p| &p.author == author).count();
+        if authored as u32 >= max {
+            return Err(StdError::generic_err(format!(
+                "too many active polls by this author. Maximum number of active polls per author is {}",
+                max
+            )));
+        }
+    }
+
+    if let Some(cooldown) = limits.author_cooldown {
+        if let Some(last_poll_time) = read_last_poll_time(&deps.storage, author)? {
+            let next_poll_time = last_poll_time.saturating_add(cooldown);
+            if current_time < next_poll_time {
+                return Err(StdError::generic_err(format!(
+                    "author can create another poll at {}",
+                    next_poll_time
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn enforce_poll_timing(limits: &PollLimits, poll_config: &PollConfig) -> StdResult<()> {
+    if let Some(max) = limits.max_voting_delay {
+        if poll_config.voting_delay.unwrap_or(0) > max {
+            return Err(StdError::generic_err(format!(
+                "voting delay is too long. Maximum voting delay is {}",
+                max
+            )));
+        }
+    }
+
+    if let Some(max) = limits.max_duration {
+        if poll_config.duration > max {
+            return Err(StdError::generic_err(format!(
+                "poll duration is too long. Maximum poll duration is {}",
+                max
+            )));
+        }
+    }
+
+    if let Some(max) = limits.max_reveal_period {
+        if poll_config.reveal_period.unwrap_or(0) > max {
+            return Err(StdError::generic_err(format!(
+                "reveal period is too long. Maximum reveal period is {}",
+                max
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Authors control their poll's config, so messages are only dispatched for polls that are at
+/// least as strict as the admin requires, and only to the contracts the admin allowed
+fn enforce_execution_rules(
+    config: &Config,
+    poll_config: &PollConfig,
+    execute_msgs: &[CosmosMsg],
+    factory: &HumanAddr,
+) -> StdResult<()> {
+    if execute_msgs.is_empty() {
+        return Ok(());
+    }
+
+    let rules = config
+        .execution_rules
+        .as_ref()
+        .ok_or_else(|| StdError::generic_err("this factory doesn't accept executable polls"))?;
+    if poll_config.quorum < rules.min_quorum || poll_config.min_threshold < rules.min_threshold {
+        return Err(StdError::generic_err(format!(
+            "executable polls require a quorum of at least {} and a threshold of at least {}",
+            rules.min_quorum, rules.min_threshold
+        )));
+    }
+
+    for msg in execute_msgs {
+        match msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. })
+                if contract_addr != factory && rules.allowed_targets.contains(contract_addr) => {}
+            _ => {
+                return Err(StdError::generic_err(
+                    "polls can only execute messages on the allowed contracts",
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the config of a poll created from `template`, with the author's overrides if any
+fn apply_template(
+    template: &PollTemplate,
+    poll_config: Option<PollConfig>,
+    poll_metadata: &PollMetadata,
+    poll_choices: &[String],
+) -> StdResult<PollConfig> {
+    if let Some(vote_type) = &template.vote_type {
+        if vote_type != &poll_metadata.vote_type {
+            return Err(StdError::generic_err(format!(
+                "this template only allows {:?} polls",
+                vote_type
+            )));
+        }
+    }
+
+    if let Some(allowed_choices) = &template.allowed_choices {
+        if let Some(choice) = poll_choices.iter().find(|c| !allowed_choices.contains(c)) {
+            return Err(StdError::generic_err(format!(
+                "choice {} is not allowed by this template",
+                choice
+            )));
+        }
+    }
+
+    let poll_config = match poll_config {
+        Some(poll_config) => poll_config,
+        None => return Ok(template.config.clone()),
+    };
+    enforce_config_bounds(&template.config, &poll_config, "its template")?;
+
+    Ok(poll_config)
+}
+
+/// Authors can only make their poll stricter than its template, or than the default config if it
+/// doesn't use one
+fn enforce_config_bounds(
+    bounds: &PollConfig,
+    poll_config: &PollConfig,
+    bounds_name: &str,
+) -> StdResult<()> {
+    if poll_config.quorum > 100 || poll_config.min_threshold > 100 {
+        return Err(StdError::generic_err(
+            "quorum and minimum threshold must be percentages between 0 and 100",
+        ));
+    }
+
+    // A poll keeps the voting mode of its bounds, and can only give revealers more time
+    let looser_reveal_period = match (bounds.reveal_period, poll_config.reveal_period) {
+        (None, None) => false,
+        (Some(min), Some(period)) => period < min,
+        _ => true,
+    };
+    if looser_reveal_period
+        || poll_config.quorum < bounds.quorum
+        || poll_config.min_threshold < bounds.min_threshold
+        || poll_config.duration < bounds.duration
+        || poll_config.voting_delay.unwrap_or(0) < bounds.voting_delay.unwrap_or(0)
+        || (bounds.snapshot_voting && !poll_config.snapshot_voting)
+        || poll_config.pass_choice != bounds.pass_choice
+        || (poll_config.quorum == bounds.quorum
+            && poll_config.quorum_inclusive
+            && !bounds.quorum_inclusive)
+        || (poll_config.min_threshold == bounds.min_threshold
+            && poll_config.threshold_inclusive
+            && !bounds.threshold_inclusive)
+    {
+        return Err(StdError::generic_err(format!(
+            "poll config can't be looser than {}",
+            bounds_name
+        )));
+    }
+
+    Ok(())
+}
+
+fn deposit_required(config: &Config) -> bool {
+    config
+        .deposit
+        .as_ref()
+        .map_or(false, |deposit| deposit.amount.u128() > 0)
+}
+
+fn poll_log(action: &str, record: &PollRecord) -> Vec<LogAttribute> {
+    vec![
+        log(log_keys::ACTION, action),
+        log(log_keys::POLL_ID, record.id),
+        log(log_keys::POLL_ADDR, &record.address),
+        log(log_keys::STATUS, record.status.as_str()),
+    ]
+}
+
 fn remove_inactive_polls<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: &Env,
@@ -443,9 +1381,1109 @@ fn enforce_admin<S: Storage, A: Api, Q: Querier>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env};
-    use cosmwasm_std::{coins, from_binary, StdError};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{coins, from_binary, BankMsg, StdError};
+    use scrt_finance::secret_vote_types::VoteType;
 
     #[test]
     fn test() {}
+
+    fn poll_record(id: u64) -> PollRecord {
+        PollRecord {
+            id,
+            address: HumanAddr(format!("poll{}", id)),
+            title: format!("poll number {}", id),
+            vote_type: VoteType::SingleChoice,
+            author: HumanAddr("author".into()),
+            author_alias: "author".into(),
+            start_time: 0,
+            end_time: 1000,
+            finalize_by: 1100,
+            status: PollStatus::Active,
+            code_hash: "poll_hash".into(),
+            label: format!("secret-poll-{}", id),
+        }
+    }
+
+    #[test]
+    fn test_poll_registry() {
+        let mut deps = mock_dependencies(20, &[]);
+
+        for id in 0..3 {
+            push_poll_record(&mut deps.storage, &poll_record(id)).unwrap();
+        }
+        let err = push_poll_record(&mut deps.storage, &poll_record(5)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll id 5 is out of order"));
+
+        // Only registered polls can report their outcome, and only once
+        let err =
+            poll_finalized(&mut deps, mock_env("stranger", &[]), PollOutcome::Passed).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+        poll_finalized(&mut deps, mock_env("poll1", &[]), PollOutcome::Passed).unwrap();
+        let err =
+            poll_finalized(&mut deps, mock_env("poll1", &[]), PollOutcome::Rejected).unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll status was already set"));
+
+        let res = query_polls(&deps, Some(PollStatus::Active), 0, 10).unwrap();
+        let first = poll_record(0);
+        let third = poll_record(2);
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Polls {
+                polls: vec![first.clone(), third],
+                total: 2,
+            })
+            .unwrap()
+        );
+
+        let res = query_polls(&deps, None, 1, 1).unwrap();
+        let mut second = poll_record(1);
+        second.status = PollStatus::Passed;
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Polls {
+                polls: vec![second.clone()],
+                total: 3,
+            })
+            .unwrap()
+        );
+
+        let res = query_polls(&deps, Some(PollStatus::Passed), 0, 10).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Polls {
+                polls: vec![second],
+                total: 1,
+            })
+            .unwrap()
+        );
+
+        // Pages past the end of a filtered list are empty but still count the matches
+        let res = query_polls(&deps, Some(PollStatus::Active), 1, u32::MAX).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Polls {
+                polls: vec![],
+                total: 2,
+            })
+            .unwrap()
+        );
+        let err = query_polls(&deps, None, u32::MAX, 10).unwrap_err();
+        assert_eq!(err, StdError::generic_err("page is out of range"));
+
+        let res = query_poll_by_id(&deps, 0).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::PollById { poll: first }).unwrap()
+        );
+        let err = query_poll_by_id(&deps, 3).unwrap_err();
+        assert_eq!(err, StdError::generic_err("no poll with id 3"));
+    }
+
+    fn config(deposit: Option<DepositConfig>) -> Config {
+        Config {
+            poll_contract: PollContract {
+                code_id: 1,
+                code_hash: "poll_hash".into(),
+            },
+            staking_pool: SecretContract {
+                address: HumanAddr("staking_pool".into()),
+                contract_hash: "staking_pool_hash".into(),
+            },
+            id_counter: 0,
+            prng_seed: [0; 32],
+            min_staked: 0,
+            reveal_com: RevealCommittee {
+                n: 1,
+                revealers: vec![],
+                timeout: None,
+                fallback_n: None,
+            },
+            deposit,
+            limits: PollLimits::default(),
+            guardian: Some(HumanAddr("guardian".into())),
+            extra_pools: vec![WeightedPool {
+                pool: SecretContract {
+                    address: HumanAddr("lp_pool".into()),
+                    contract_hash: "lp_pool_hash".into(),
+                },
+                multiplier: 20_000,
+            }],
+            execution_rules: Some(ExecutionRules {
+                min_quorum: 20,
+                min_threshold: 50,
+                allowed_targets: vec![
+                    HumanAddr("treasury".into()),
+                    HumanAddr(MOCK_CONTRACT_ADDR.into()),
+                ],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_deposit_settlement() {
+        let mut deps = mock_dependencies(20, &[]);
+        let token = SecretContract {
+            address: HumanAddr("sefi".into()),
+            contract_hash: "sefi_hash".into(),
+        };
+        let deposit = DepositConfig {
+            token: token.clone(),
+            amount: Uint128(100),
+            slash_recipient: None,
+            min_quorum: 10,
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(Some(deposit)))
+            .unwrap();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(DEFAULT_POLL_CONFIG_KEY, &poll_config(0, 0))
+            .unwrap();
+
+        for id in 0..2 {
+            let record = poll_record(id);
+            push_poll_record(&mut deps.storage, &record).unwrap();
+            let mut deposits_store = PrefixedStorage::new(DEPOSITS_KEY, &mut deps.storage);
+            TypedStoreMut::attach(&mut deposits_store)
+                .store(
+                    record.address.0.as_bytes(),
+                    &PollDeposit {
+                        depositor: HumanAddr("author".into()),
+                        token: token.clone(),
+                        amount: Uint128(100),
+                        status: DepositStatus::Held,
+                    },
+                )
+                .unwrap();
+        }
+
+        // A rejected poll still reached quorum, so the deposit is refunded
+        let res = poll_finalized(&mut deps, mock_env("poll0", &[]), PollOutcome::Rejected).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![snip20::transfer_msg(
+                HumanAddr("author".into()),
+                Uint128(100),
+                None,
+                256,
+                token.contract_hash.clone(),
+                token.address.clone(),
+            )
+            .unwrap()]
+        );
+
+        let res = poll_finalized(
+            &mut deps,
+            mock_env("poll1", &[]),
+            PollOutcome::QuorumNotReached,
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages,
+            vec![snip20::burn_msg(
+                Uint128(100),
+                None,
+                256,
+                token.contract_hash.clone(),
+                token.address.clone(),
+            )
+            .unwrap()]
+        );
+
+        let res = query_poll_deposit(&deps, HumanAddr("poll1".into())).unwrap();
+        let answer: QueryAnswer = from_binary(&res).unwrap();
+        match answer {
+            QueryAnswer::PollDeposit { deposit } => {
+                assert_eq!(deposit.unwrap().status, DepositStatus::Slashed)
+            }
+            _ => panic!("unexpected query answer"),
+        }
+
+        // A poll that takes a deposit can't dodge slashing with a low quorum
+        let err = handle(
+            &mut deps,
+            mock_env("sefi", &[]),
+            PollFactoryHandleMsg::Receive {
+                sender: HumanAddr("author".into()),
+                from: HumanAddr("author".into()),
+                amount: Uint128(100),
+                msg: to_binary(&PollFactoryReceiveMsg::NewPoll {
+                    poll_metadata: PollMetadata {
+                        title: "zero quorum".into(),
+                        description: "".into(),
+                        vote_type: VoteType::SingleChoice,
+                        author_addr: None,
+                        author_alias: "author".into(),
+                    },
+                    template: None,
+                    poll_config: Some(poll_config(5, 50)),
+                    poll_choices: vec!["Yes".into(), "No".into()],
+                    pool_viewing_key: "key".into(),
+                    execute_msgs: None,
+                })
+                .unwrap(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("polls that take a deposit need a quorum of at least 10")
+        );
+    }
+
+    #[test]
+    fn test_settle_unfinalized_deposit() {
+        let mut deps = mock_dependencies(20, &[]);
+        let token = SecretContract {
+            address: HumanAddr("sefi".into()),
+            contract_hash: "sefi_hash".into(),
+        };
+        let deposit = DepositConfig {
+            token: token.clone(),
+            amount: Uint128(100),
+            slash_recipient: None,
+            min_quorum: 10,
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(Some(deposit)))
+            .unwrap();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(ADMIN_KEY, &HumanAddr("admin".into()))
+            .unwrap();
+
+        for id in 0..2 {
+            let record = poll_record(id);
+            push_poll_record(&mut deps.storage, &record).unwrap();
+            let mut deposits_store = PrefixedStorage::new(DEPOSITS_KEY, &mut deps.storage);
+            TypedStoreMut::attach(&mut deposits_store)
+                .store(
+                    record.address.0.as_bytes(),
+                    &PollDeposit {
+                        depositor: HumanAddr("author".into()),
+                        token: token.clone(),
+                        amount: Uint128(100),
+                        status: DepositStatus::Held,
+                    },
+                )
+                .unwrap();
+        }
+        let settle_env = |sender: &str, time: u64| {
+            let mut env = mock_env(sender, &[]);
+            env.block.time = time;
+            env
+        };
+        // There's no slash recipient, so slashed deposits are burned
+        let slash = snip20::burn_msg(
+            Uint128(100),
+            None,
+            256,
+            token.contract_hash.clone(),
+            token.address.clone(),
+        )
+        .unwrap();
+
+        // The admin can settle once the poll's committee deadline has passed, not when it ends
+        let poll0 = HumanAddr("poll0".into());
+        let err = settle_unfinalized_deposit(&mut deps, settle_env("admin", 1100), poll0.clone())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("this poll's deposit can't be settled yet")
+        );
+        let res = settle_unfinalized_deposit(&mut deps, settle_env("admin", 1101), poll0.clone())
+            .unwrap();
+        assert_eq!(res.messages, vec![slash.clone()]);
+        let err =
+            settle_unfinalized_deposit(&mut deps, settle_env("admin", 1101), poll0).unwrap_err();
+        assert_eq!(err, StdError::generic_err("this poll has no held deposit"));
+
+        // Finalizing the poll later records its outcome without settling the deposit again
+        let res = poll_finalized(
+            &mut deps,
+            mock_env("poll0", &[]),
+            PollOutcome::QuorumNotReached,
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(
+            read_poll_record(&deps.storage, 0).unwrap().status,
+            PollStatus::QuorumNotReached
+        );
+
+        // Anyone else has to wait for the settlement timeout
+        let poll1 = HumanAddr("poll1".into());
+        let err = settle_unfinalized_deposit(
+            &mut deps,
+            settle_env("author", 1100 + DEPOSIT_SETTLEMENT_TIMEOUT),
+            poll1.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("this poll's deposit can't be settled yet")
+        );
+        let res = settle_unfinalized_deposit(
+            &mut deps,
+            settle_env("author", 1101 + DEPOSIT_SETTLEMENT_TIMEOUT),
+            poll1,
+        )
+        .unwrap();
+        assert_eq!(res.messages, vec![slash]);
+    }
+
+    #[test]
+    fn test_poll_limits() {
+        let mut deps = mock_dependencies(20, &[]);
+        let author = HumanAddr("author".into());
+        let active_poll = |id: u64, author: &str| ActivePoll {
+            address: HumanAddr(format!("poll{}", id)),
+            hash: "poll_hash".into(),
+            start_time: 0,
+            end_time: 1000,
+            author: HumanAddr(author.into()),
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(
+                ACTIVE_POLLS_KEY,
+                &vec![active_poll(0, "author"), active_poll(1, "someone else")],
+            )
+            .unwrap();
+
+        let limits = PollLimits {
+            max_active_polls: Some(2),
+            max_active_polls_per_author: None,
+            author_cooldown: None,
+            max_voting_delay: None,
+            max_duration: None,
+            max_reveal_period: None,
+        };
+        let err = enforce_poll_limits(&deps, &limits, &author, 500).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("too many active polls. Maximum number of active polls is 2")
+        );
+        // Ended polls don't count
+        enforce_poll_limits(&deps, &limits, &author, 1001).unwrap();
+
+        let limits = PollLimits {
+            max_active_polls: None,
+            max_active_polls_per_author: Some(1),
+            author_cooldown: None,
+            max_voting_delay: None,
+            max_duration: None,
+            max_reveal_period: None,
+        };
+        enforce_poll_limits(&deps, &limits, &HumanAddr("newcomer".into()), 500).unwrap();
+        let err = enforce_poll_limits(&deps, &limits, &author, 500).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err(
+                "too many active polls by this author. Maximum number of active polls per author is 1"
+            )
+        );
+
+        let limits = PollLimits {
+            max_active_polls: None,
+            max_active_polls_per_author: None,
+            author_cooldown: Some(100),
+            max_voting_delay: None,
+            max_duration: None,
+            max_reveal_period: None,
+        };
+        enforce_poll_limits(&deps, &limits, &author, 500).unwrap();
+        store_last_poll_time(&mut deps.storage, &author, 500).unwrap();
+        let err = enforce_poll_limits(&deps, &limits, &author, 599).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("author can create another poll at 600")
+        );
+        enforce_poll_limits(&deps, &limits, &author, 600).unwrap();
+
+        let limits = PollLimits {
+            max_voting_delay: Some(100),
+            max_duration: Some(1000),
+            max_reveal_period: Some(500),
+            ..PollLimits::default()
+        };
+        let mut poll_config = poll_config(0, 0);
+        enforce_poll_timing(&limits, &poll_config).unwrap();
+        poll_config.voting_delay = Some(101);
+        let err = enforce_poll_timing(&limits, &poll_config).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("voting delay is too long. Maximum voting delay is 100")
+        );
+        poll_config.voting_delay = Some(100);
+        poll_config.duration = 1001;
+        let err = enforce_poll_timing(&limits, &poll_config).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll duration is too long. Maximum poll duration is 1000")
+        );
+        poll_config.duration = 1000;
+        poll_config.reveal_period = Some(501);
+        let err = enforce_poll_timing(&limits, &poll_config).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("reveal period is too long. Maximum reveal period is 500")
+        );
+        poll_config.reveal_period = None;
+
+        // Without limits, a poll still can't end past the end of time
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(None))
+            .unwrap();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(DEFAULT_POLL_CONFIG_KEY, &poll_config(0, 0))
+            .unwrap();
+        poll_config.duration = u64::MAX;
+        let err = handle(
+            &mut deps,
+            mock_env("author", &[]),
+            PollFactoryHandleMsg::NewPoll {
+                poll_metadata: PollMetadata {
+                    title: "forever".into(),
+                    description: "".into(),
+                    vote_type: VoteType::SingleChoice,
+                    author_addr: None,
+                    author_alias: "author".into(),
+                },
+                template: None,
+                poll_config: Some(poll_config),
+                poll_choices: vec!["Yes".into(), "No".into()],
+                pool_viewing_key: "key".into(),
+                execute_msgs: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("voting delay and duration are too long")
+        );
+    }
+
+    #[test]
+    fn test_pending_polls() {
+        let mut deps = mock_dependencies(20, &[]);
+        let mut env = mock_env("author", &[]);
+        env.block.time = 100;
+
+        // Two polls created in the same block
+        let challenges: Vec<Challenge> = (0..2)
+            .map(|id| {
+                let challenge = Challenge::new(&env, &[0u8; 32], id);
+                let mut record = poll_record(id);
+                record.address = HumanAddr::default();
+                push_poll_record(&mut deps.storage, &record).unwrap();
+                store_pending_poll(
+                    &mut deps.storage,
+                    id,
+                    Some(&PendingPoll {
+                        challenge: challenge.clone(),
+                        expires_at: env.block.time + CHALLENGE_TTL,
+                        execute_msgs: vec![],
+                        deposit: None,
+                    }),
+                )
+                .unwrap();
+                challenge
+            })
+            .collect();
+        assert_ne!(challenges[0].to_string(), challenges[1].to_string());
+
+        let poll_env = |poll: &str, time: u64| {
+            let mut env = mock_env(poll, &[]);
+            env.block.time = time;
+            env
+        };
+        let err = register_for_updates(
+            &mut deps,
+            poll_env("poll1", 100),
+            challenges[0].clone(),
+            1,
+            1000,
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("challenge did not match. This function can be called only as a callback from a new poll contract"));
+
+        // The second poll registering first doesn't lock out the first one
+        register_for_updates(
+            &mut deps,
+            poll_env("poll1", 100),
+            challenges[1].clone(),
+            1,
+            1000,
+        )
+        .unwrap();
+        let err = register_for_updates(
+            &mut deps,
+            poll_env("poll1", 100),
+            challenges[1].clone(),
+            1,
+            1000,
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("no pending poll with id 1"));
+
+        let err = register_for_updates(
+            &mut deps,
+            poll_env("poll0", 100 + CHALLENGE_TTL + 1),
+            challenges[0].clone(),
+            0,
+            1000,
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("challenge has expired"));
+        register_for_updates(
+            &mut deps,
+            poll_env("poll0", 100),
+            challenges[0].clone(),
+            0,
+            1000,
+        )
+        .unwrap();
+
+        for id in 0..2 {
+            let poll = read_poll_record(&deps.storage, id).unwrap();
+            assert_eq!(poll.address, HumanAddr(format!("poll{}", id)));
+            assert_eq!(poll.code_hash, "poll_hash");
+            assert_eq!(poll.label, format!("secret-poll-{}", id));
+            assert_eq!(
+                read_poll_id(&deps.storage, &poll.address).unwrap(),
+                Some(id)
+            );
+        }
+        let active_polls: Vec<ActivePoll> = TypedStore::attach(&deps.storage)
+            .load(ACTIVE_POLLS_KEY)
+            .unwrap();
+        assert_eq!(active_polls.len(), 2);
+    }
+
+    #[test]
+    fn test_voter_registration() {
+        let mut deps = mock_dependencies(20, &[]);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(None))
+            .unwrap();
+        let active_poll = |id: u64, end_time: u64| ActivePoll {
+            address: HumanAddr(format!("poll{}", id)),
+            hash: "poll_hash".into(),
+            start_time: 0,
+            end_time,
+            author: HumanAddr("author".into()),
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(
+                ACTIVE_POLLS_KEY,
+                &vec![
+                    active_poll(0, 1000),
+                    active_poll(1, 2000),
+                    active_poll(2, 2000),
+                ],
+            )
+            .unwrap();
+
+        let voter = HumanAddr("voter".into());
+        let err = register_voter(&mut deps, mock_env("stranger", &[]), voter.clone()).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+        for poll in &["poll0", "poll1", "poll1"] {
+            let mut env = mock_env(*poll, &[]);
+            env.block.time = 500;
+            register_voter(&mut deps, env, voter.clone()).unwrap();
+        }
+
+        // Only polls the voter voted in and that haven't ended get the update
+        let mut env = mock_env("staking_pool", &[]);
+        env.block.time = 1500;
+        let res = update_voting_power(&mut deps, env, voter.clone(), Uint128(10)).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: HumanAddr("poll1".into()),
+                callback_code_hash: "poll_hash".into(),
+                msg: to_binary(&PollHandleMsg::UpdateVotingPower {
+                    voter: voter.clone(),
+                    new_power: Uint128(10),
+                    pool: Some(HumanAddr("staking_pool".into())),
+                })
+                .unwrap(),
+                send: vec![],
+            })]
+        );
+        assert_eq!(
+            read_voter_polls(&deps.storage, &voter).unwrap(),
+            vec![active_poll(1, 2000)]
+        );
+
+        let res = update_voting_power(
+            &mut deps,
+            mock_env("staking_pool", &[]),
+            HumanAddr("non_voter".into()),
+            Uint128(10),
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+
+        // Every registered pool can report power changes
+        let mut env = mock_env("lp_pool", &[]);
+        env.block.time = 1500;
+        let res = update_voting_power(&mut deps, env, voter.clone(), Uint128(10)).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        let err = update_voting_power(
+            &mut deps,
+            mock_env("stranger", &[]),
+            voter.clone(),
+            Uint128(10),
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+    }
+
+    #[test]
+    fn test_cancel_poll() {
+        let mut deps = mock_dependencies(20, &[]);
+        let token = SecretContract {
+            address: HumanAddr("sefi".into()),
+            contract_hash: "sefi_hash".into(),
+        };
+        let deposit = DepositConfig {
+            token: token.clone(),
+            amount: Uint128(100),
+            slash_recipient: Some(HumanAddr("dev_fund".into())),
+            min_quorum: 0,
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(Some(deposit)))
+            .unwrap();
+
+        let mut active_polls = vec![];
+        for id in 0..3 {
+            let record = poll_record(id);
+            push_poll_record(&mut deps.storage, &record).unwrap();
+            active_polls.push(ActivePoll {
+                address: record.address.clone(),
+                hash: "poll_hash".into(),
+                start_time: record.start_time,
+                end_time: record.end_time,
+                author: record.author.clone(),
+            });
+            let mut deposits_store = PrefixedStorage::new(DEPOSITS_KEY, &mut deps.storage);
+            TypedStoreMut::attach(&mut deposits_store)
+                .store(
+                    record.address.0.as_bytes(),
+                    &PollDeposit {
+                        depositor: HumanAddr("author".into()),
+                        token: token.clone(),
+                        amount: Uint128(100),
+                        status: DepositStatus::Held,
+                    },
+                )
+                .unwrap();
+        }
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(ACTIVE_POLLS_KEY, &active_polls)
+            .unwrap();
+
+        let poll0 = HumanAddr("poll0".into());
+        let err = cancel_poll(&mut deps, mock_env("author", &[]), poll0.clone()).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+
+        // A guardian veto slashes the deposit and cancels the poll itself
+        let res = cancel_poll(&mut deps, mock_env("guardian", &[]), poll0.clone()).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![
+                snip20::transfer_msg(
+                    HumanAddr("dev_fund".into()),
+                    Uint128(100),
+                    None,
+                    256,
+                    token.contract_hash.clone(),
+                    token.address.clone(),
+                )
+                .unwrap(),
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: poll0.clone(),
+                    callback_code_hash: "poll_hash".into(),
+                    msg: to_binary(&PollHandleMsg::Cancel {}).unwrap(),
+                    send: vec![],
+                }),
+            ]
+        );
+        assert_eq!(
+            read_poll_record(&deps.storage, 0).unwrap().status,
+            PollStatus::Cancelled
+        );
+        assert_eq!(
+            res.log,
+            vec![
+                log(log_keys::ACTION, log_actions::CANCEL),
+                log(log_keys::POLL_ID, 0),
+                log(log_keys::POLL_ADDR, &poll0),
+                log(log_keys::STATUS, "cancelled"),
+            ]
+        );
+        let active_polls: Vec<ActivePoll> = TypedStore::attach(&deps.storage)
+            .load(ACTIVE_POLLS_KEY)
+            .unwrap();
+        assert_eq!(active_polls.len(), 2);
+        assert_eq!(active_polls[0].address, HumanAddr("poll1".into()));
+
+        // An author cancellation is reported by the poll and refunds the deposit before voting ends
+        let cancel_env = |sender: &str, time: u64| {
+            let mut env = mock_env(sender, &[]);
+            env.block.time = time;
+            env
+        };
+        let res = poll_cancelled(&mut deps, cancel_env("poll1", 1000)).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![snip20::transfer_msg(
+                HumanAddr("author".into()),
+                Uint128(100),
+                None,
+                256,
+                token.contract_hash.clone(),
+                token.address.clone(),
+            )
+            .unwrap()]
+        );
+        let active_polls: Vec<ActivePoll> = TypedStore::attach(&deps.storage)
+            .load(ACTIVE_POLLS_KEY)
+            .unwrap();
+        assert_eq!(active_polls.len(), 1);
+
+        let err = poll_cancelled(&mut deps, cancel_env("poll1", 1000)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll status was already set"));
+
+        // Once voting ended, the deposit is slashed
+        let res = poll_cancelled(&mut deps, cancel_env("poll2", 1001)).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![snip20::transfer_msg(
+                HumanAddr("dev_fund".into()),
+                Uint128(100),
+                None,
+                256,
+                token.contract_hash.clone(),
+                token.address.clone(),
+            )
+            .unwrap()]
+        );
+        let active_polls: Vec<ActivePoll> = TypedStore::attach(&deps.storage)
+            .load(ACTIVE_POLLS_KEY)
+            .unwrap();
+        assert!(active_polls.is_empty());
+
+        // Once the admin removes the guardian, nobody can veto polls
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(ADMIN_KEY, &HumanAddr("admin".into()))
+            .unwrap();
+        let err = remove_guardian(&mut deps, mock_env("guardian", &[])).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+        remove_guardian(&mut deps, mock_env("admin", &[])).unwrap();
+        let res = query_guardian(&deps).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Guardian { address: None }).unwrap()
+        );
+        let err = remove_guardian(&mut deps, mock_env("admin", &[])).unwrap_err();
+        assert_eq!(err, StdError::generic_err("there is no guardian to remove"));
+        let err = cancel_poll(&mut deps, mock_env("guardian", &[]), poll0).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+    }
+
+    #[test]
+    fn test_templates() {
+        let mut deps = mock_dependencies(20, &[]);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(ADMIN_KEY, &HumanAddr("admin".into()))
+            .unwrap();
+
+        let treasury = PollTemplate {
+            config: PollConfig {
+                duration: 1000,
+                quorum: 30,
+                min_threshold: 50,
+                quorum_inclusive: false,
+                threshold_inclusive: false,
+                reveal_period: None,
+                voting_delay: Some(100),
+                snapshot_voting: false,
+                pass_choice: 0,
+            },
+            min_staked: Some(Uint128(1_000_000)),
+            vote_type: Some(VoteType::SingleChoice),
+            allowed_choices: Some(vec!["Yes".into(), "No".into()]),
+        };
+        let err = set_template(
+            &mut deps,
+            mock_env("author", &[]),
+            "treasury".into(),
+            Some(treasury.clone()),
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+        for name in &["treasury", "signalling", "treasury"] {
+            set_template(
+                &mut deps,
+                mock_env("admin", &[]),
+                name.to_string(),
+                Some(treasury.clone()),
+            )
+            .unwrap();
+        }
+        set_template(&mut deps, mock_env("admin", &[]), "signalling".into(), None).unwrap();
+        let err =
+            set_template(&mut deps, mock_env("admin", &[]), "signalling".into(), None).unwrap_err();
+        assert_eq!(err, StdError::generic_err("no template named signalling"));
+
+        let templates = read_templates(&deps.storage).unwrap();
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates[0].0, "treasury");
+
+        let metadata = PollMetadata {
+            title: "spend".into(),
+            description: "spend some of the treasury".into(),
+            vote_type: VoteType::SingleChoice,
+            author_addr: None,
+            author_alias: "author".into(),
+        };
+        let choices = vec!["Yes".to_string(), "No".to_string()];
+        let config = apply_template(&treasury, None, &metadata, &choices).unwrap();
+        assert_eq!(config.quorum, 30);
+
+        let err = apply_template(
+            &treasury,
+            None,
+            &metadata,
+            &["Yes".to_string(), "Maybe".to_string()],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("choice Maybe is not allowed by this template")
+        );
+
+        // Authors can only make the template stricter
+        let mut stricter = treasury.config.clone();
+        stricter.quorum = 40;
+        stricter.duration = 2000;
+        let config = apply_template(&treasury, Some(stricter), &metadata, &choices).unwrap();
+        assert_eq!((config.quorum, config.duration), (40, 2000));
+
+        let mut looser = treasury.config.clone();
+        looser.quorum_inclusive = true;
+        let err = apply_template(&treasury, Some(looser), &metadata, &choices).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll config can't be looser than its template")
+        );
+
+        // A "No" win can't be turned into a pass
+        let mut flipped = treasury.config.clone();
+        flipped.pass_choice = 1;
+        let err = apply_template(&treasury, Some(flipped), &metadata, &choices).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll config can't be looser than its template")
+        );
+
+        // Nor can a poll switch to commit-reveal voting
+        let mut committed = treasury.config.clone();
+        committed.reveal_period = Some(u64::MAX);
+        let err = apply_template(&treasury, Some(committed), &metadata, &choices).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll config can't be looser than its template")
+        );
+
+        let mut impossible = treasury.config.clone();
+        impossible.quorum = 101;
+        let err = apply_template(&treasury, Some(impossible), &metadata, &choices).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err(
+                "quorum and minimum threshold must be percentages between 0 and 100"
+            )
+        );
+
+        // Polls without a template are bound by the default config instead
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(None))
+            .unwrap();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(DEFAULT_POLL_CONFIG_KEY, &treasury.config)
+            .unwrap();
+        let err = handle(
+            &mut deps,
+            mock_env("author", &[]),
+            PollFactoryHandleMsg::NewPoll {
+                poll_metadata: metadata,
+                template: None,
+                poll_config: Some(poll_config(0, 0)),
+                poll_choices: choices,
+                pool_viewing_key: "key".into(),
+                execute_msgs: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll config can't be looser than the default config")
+        );
+    }
+
+    fn execute_msg(contract: &str) -> CosmosMsg {
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: HumanAddr(contract.into()),
+            callback_code_hash: "hash".into(),
+            msg: Binary::from(b"{}".to_vec()),
+            send: vec![],
+        })
+    }
+
+    fn poll_config(quorum: u8, min_threshold: u8) -> PollConfig {
+        PollConfig {
+            duration: 1000,
+            quorum,
+            min_threshold,
+            quorum_inclusive: false,
+            threshold_inclusive: false,
+            reveal_period: None,
+            voting_delay: None,
+            snapshot_voting: false,
+            pass_choice: 0,
+        }
+    }
+
+    #[test]
+    fn test_execution_rules() {
+        let mut deps = mock_dependencies(20, &[]);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(None))
+            .unwrap();
+        // The default config alone doesn't stop this
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(DEFAULT_POLL_CONFIG_KEY, &poll_config(0, 0))
+            .unwrap();
+
+        // An author can't create a poll that passes on their own vote and executes messages
+        let err = handle(
+            &mut deps,
+            mock_env("author", &[]),
+            PollFactoryHandleMsg::NewPoll {
+                poll_metadata: PollMetadata {
+                    title: "self-pass".into(),
+                    description: "".into(),
+                    vote_type: VoteType::SingleChoice,
+                    author_addr: None,
+                    author_alias: "author".into(),
+                },
+                template: None,
+                poll_config: Some(poll_config(0, 0)),
+                poll_choices: vec!["Yes".into(), "No".into()],
+                pool_viewing_key: "key".into(),
+                execute_msgs: Some(vec![execute_msg("treasury")]),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err(
+                "executable polls require a quorum of at least 20 and a threshold of at least 50"
+            )
+        );
+
+        let factory_config = config(None);
+        let factory = HumanAddr(MOCK_CONTRACT_ADDR.into());
+        enforce_execution_rules(
+            &factory_config,
+            &poll_config(20, 50),
+            &[execute_msg("treasury")],
+            &factory,
+        )
+        .unwrap();
+
+        // Messages can only target the allowed contracts, and never the factory itself
+        let bank_msg = CosmosMsg::Bank(BankMsg::Send {
+            from_address: factory.clone(),
+            to_address: HumanAddr("author".into()),
+            amount: coins(1, "uscrt"),
+        });
+        for msg in vec![
+            execute_msg("stranger"),
+            execute_msg(MOCK_CONTRACT_ADDR),
+            bank_msg,
+        ] {
+            let err =
+                enforce_execution_rules(&factory_config, &poll_config(20, 50), &[msg], &factory)
+                    .unwrap_err();
+            assert_eq!(
+                err,
+                StdError::generic_err("polls can only execute messages on the allowed contracts")
+            );
+        }
+
+        // Without rules no poll can execute messages, plain polls are unaffected
+        let mut factory_config = config(None);
+        factory_config.execution_rules = None;
+        let err = enforce_execution_rules(
+            &factory_config,
+            &poll_config(100, 100),
+            &[execute_msg("treasury")],
+            &factory,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("this factory doesn't accept executable polls")
+        );
+        enforce_execution_rules(&factory_config, &poll_config(0, 0), &[], &factory).unwrap();
+    }
+
+    #[test]
+    fn test_execute_poll() {
+        let mut deps = mock_dependencies(20, &[]);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config(None))
+            .unwrap();
+        for id in 0..2 {
+            let record = poll_record(id);
+            push_poll_record(&mut deps.storage, &record).unwrap();
+            let mut executions_store = PrefixedStorage::new(EXECUTIONS_KEY, &mut deps.storage);
+            TypedStoreMut::attach(&mut executions_store)
+                .store(
+                    record.address.0.as_bytes(),
+                    &PollExecution {
+                        messages: vec![execute_msg("treasury")],
+                        executed: false,
+                    },
+                )
+                .unwrap();
+        }
+
+        // The factory doesn't take the poll's word for it, the recorded outcome must be passed
+        let err = execute_poll(&mut deps, mock_env("poll0", &[])).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("only passed polls can be executed")
+        );
+
+        poll_finalized(&mut deps, mock_env("poll0", &[]), PollOutcome::Passed).unwrap();
+        poll_finalized(&mut deps, mock_env("poll1", &[]), PollOutcome::Rejected).unwrap();
+
+        let res = execute_poll(&mut deps, mock_env("poll0", &[])).unwrap();
+        assert_eq!(res.messages, vec![execute_msg("treasury")]);
+        let err = execute_poll(&mut deps, mock_env("poll0", &[])).unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll was already executed"));
+
+        let err = execute_poll(&mut deps, mock_env("poll1", &[])).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("only passed polls can be executed")
+        );
+        let err = execute_poll(&mut deps, mock_env("stranger", &[])).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+    }
 }
@@ -1,7 +1,10 @@
-use crate::state::ActivePoll;
+use crate::state::{ActivePoll, PollDeposit, PollExecution, PollRecord, PollStatus};
 use cosmwasm_std::{Binary, HumanAddr, Uint128};
 use schemars::JsonSchema;
-use scrt_finance::secret_vote_types::{PollConfig, PollContract, RevealCommittee};
+use scrt_finance::secret_vote_types::{
+    DepositConfig, ExecutionRules, PollConfig, PollContract, PollLimits, PollTemplate,
+    RevealCommittee, WeightedPool,
+};
 use scrt_finance::types::SecretContract;
 use serde::{Deserialize, Serialize};
 
@@ -11,8 +14,13 @@ pub struct InitMsg {
     pub poll_contract: PollContract,
     pub staking_pool: SecretContract,
     pub default_poll_config: PollConfig,
-    pub min_staked: Uint128,
+    pub min_staked: Uint128, // Staked in the SEFI staking pool, extra pools don't count
     pub reveal_com: RevealCommittee,
+    pub deposit: Option<DepositConfig>,
+    pub limits: Option<PollLimits>,
+    pub guardian: Option<HumanAddr>,
+    pub extra_pools: Option<Vec<WeightedPool>>,
+    pub execution_rules: Option<ExecutionRules>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -23,10 +31,30 @@ pub enum QueryMsg {
     },
     DefaultPollConfig {},
     StakingPool {},
+    ExtraPools {},
     PollCode {},
     Admin {},
     RevealCommittee {},
     MinimumStake {},
+    PollLimits {},
+    Guardian {},
+    ExecutionRules {},
+    Templates {},
+    PollExecution {
+        poll: HumanAddr,
+    },
+    Polls {
+        status: Option<PollStatus>,
+        page: u32,
+        page_size: u32,
+    },
+    PollById {
+        id: u64,
+    },
+    ProposalDeposit {},
+    PollDeposit {
+        poll: HumanAddr,
+    },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -35,10 +63,26 @@ pub enum QueryAnswer {
     ActivePolls { active_polls: Vec<ActivePoll> },
     DefaultPollConfig { poll_config: PollConfig },
     StakingPool { contract: SecretContract },
+    ExtraPools { pools: Vec<WeightedPool> },
     PollCode { contract: PollContract },
     Admin { address: HumanAddr },
     RevealCommittee { committee: RevealCommittee },
     MinimumStake { amount: Uint128 },
+    PollLimits { limits: PollLimits },
+    Guardian { address: Option<HumanAddr> },
+    ExecutionRules { rules: Option<ExecutionRules> },
+    Templates { templates: Vec<NamedTemplate> },
+    PollExecution { execution: Option<PollExecution> },
+    Polls { polls: Vec<PollRecord>, total: u64 },
+    PollById { poll: PollRecord },
+    ProposalDeposit { deposit: Option<DepositConfig> },
+    PollDeposit { deposit: Option<PollDeposit> },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct NamedTemplate {
+    pub name: String,
+    pub template: PollTemplate,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -1,14 +1,33 @@
-use cosmwasm_std::HumanAddr;
+use crate::challenge::Challenge;
+use cosmwasm_std::{CosmosMsg, HumanAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
 use schemars::JsonSchema;
-use scrt_finance::secret_vote_types::{PollContract, RevealCommittee};
+use scrt_finance::secret_vote_types::{
+    DepositConfig, ExecutionRules, PollContract, PollLimits, PollOutcome, PollTemplate,
+    RevealCommittee, VoteType, WeightedPool,
+};
 use scrt_finance::types::SecretContract;
+use secret_toolkit::storage::{AppendStore, AppendStoreMut, TypedStore, TypedStoreMut};
 use serde::{Deserialize, Serialize};
 
 pub const ADMIN_KEY: &[u8] = b"admin";
 pub const CONFIG_KEY: &[u8] = b"config";
 pub const DEFAULT_POLL_CONFIG_KEY: &[u8] = b"defaultconfig";
-pub const CURRENT_CHALLENGE_KEY: &[u8] = b"prngseed";
 pub const ACTIVE_POLLS_KEY: &[u8] = b"active_polls";
+pub const EXECUTIONS_KEY: &[u8] = b"executions";
+pub const PENDING_POLLS_KEY: &[u8] = b"pendingpolls";
+pub const POLLS_KEY: &[u8] = b"polls";
+pub const POLL_IDS_KEY: &[u8] = b"pollids";
+pub const STATUS_INDEX_KEY: &[u8] = b"statusindex";
+pub const STATUS_POSITIONS_KEY: &[u8] = b"statuspositions";
+pub const DEPOSITS_KEY: &[u8] = b"deposits";
+pub const LAST_POLL_KEY: &[u8] = b"lastpoll";
+pub const VOTER_POLLS_KEY: &[u8] = b"voterpolls";
+pub const TEMPLATES_KEY: &[u8] = b"templates";
+pub const TEMPLATE_NAMES_KEY: &[u8] = b"templatenames";
+
+pub const MAX_PAGE_SIZE: u32 = 100;
+pub const DEPOSIT_SETTLEMENT_TIMEOUT: u64 = 60 * 60 * 24 * 30; // Seconds after `finalize_by`
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
@@ -18,11 +37,368 @@ pub struct Config {
     pub prng_seed: [u8; 32],
     pub min_staked: u128,
     pub reveal_com: RevealCommittee,
+    pub deposit: Option<DepositConfig>,
+    pub limits: PollLimits,
+    pub guardian: Option<HumanAddr>, // Can cancel any poll before it's finalized
+    pub extra_pools: Vec<WeightedPool>,
+    pub execution_rules: Option<ExecutionRules>, // Executable proposals are rejected if not set
 }
 
-#[derive(Serialize, Deserialize, JsonSchema)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct ActivePoll {
     pub address: HumanAddr,
     pub hash: String,
+    pub start_time: u64, // Voting opens after the poll's discussion period
+    pub end_time: u64,
+    pub author: HumanAddr,
+}
+
+// A poll that was instantiated but hasn't registered itself with the factory yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingPoll {
+    pub challenge: Challenge,
+    pub expires_at: u64,
+    pub execute_msgs: Vec<CosmosMsg>,
+    pub deposit: Option<PollDeposit>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct PollExecution {
+    pub messages: Vec<CosmosMsg>,
+    pub executed: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositStatus {
+    Held,
+    Refunded,
+    Slashed,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PollDeposit {
+    pub depositor: HumanAddr,
+    pub token: SecretContract, // The deposit token may change after the poll was created
+    pub amount: Uint128,
+    pub status: DepositStatus,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollStatus {
+    Active,
+    Passed,
+    Rejected,
+    QuorumNotReached,
+    Cancelled,
+}
+
+impl PollStatus {
+    // Matches the serialized form, used as the `status` log attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PollStatus::Active => "active",
+            PollStatus::Passed => PollOutcome::Passed.as_str(),
+            PollStatus::Rejected => PollOutcome::Rejected.as_str(),
+            PollStatus::QuorumNotReached => PollOutcome::QuorumNotReached.as_str(),
+            PollStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl From<PollOutcome> for PollStatus {
+    fn from(outcome: PollOutcome) -> Self {
+        match outcome {
+            PollOutcome::Passed => PollStatus::Passed,
+            PollOutcome::Rejected => PollStatus::Rejected,
+            PollOutcome::QuorumNotReached => PollStatus::QuorumNotReached,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PollRecord {
+    pub id: u64,
+    pub address: HumanAddr,
+    pub title: String,
+    pub vote_type: VoteType,
+    pub author: HumanAddr,
+    pub author_alias: String,
+    pub start_time: u64,
     pub end_time: u64,
+    pub finalize_by: u64, // Once revealing ends and the reveal committee's timeout passes
+    pub status: PollStatus,
+    pub code_hash: String, // Of the poll contract code the poll was instantiated from
+    pub label: String,
+}
+
+// Poll ids are assigned sequentially, so a poll's id is also its position in the registry
+pub fn push_poll_record<S: Storage>(storage: &mut S, record: &PollRecord) -> StdResult<()> {
+    let mut polls_store = PrefixedStorage::new(POLLS_KEY, storage);
+    let mut polls_store = AppendStoreMut::attach_or_create(&mut polls_store)?;
+    if polls_store.len() as u64 != record.id {
+        return Err(StdError::generic_err(format!(
+            "poll id {} is out of order",
+            record.id
+        )));
+    }
+    polls_store.push(record)?;
+    push_status_index(storage, &record.status, record.id)?;
+
+    // Pending polls are pushed without an address and get one when they register themselves
+    if record.address == HumanAddr::default() {
+        return Ok(());
+    }
+    let mut ids_store = PrefixedStorage::new(POLL_IDS_KEY, storage);
+    TypedStoreMut::attach(&mut ids_store).store(record.address.0.as_bytes(), &record.id)
+}
+
+pub fn set_poll_address<S: Storage>(
+    storage: &mut S,
+    id: u64,
+    address: &HumanAddr,
+) -> StdResult<PollRecord> {
+    let mut record = read_poll_record(storage, id)?;
+    record.address = address.clone();
+
+    let mut polls_store = PrefixedStorage::new(POLLS_KEY, storage);
+    let mut polls_store = AppendStoreMut::<PollRecord, _>::attach_or_create(&mut polls_store)?;
+    polls_store.set_at(id as u32, &record)?;
+
+    let mut ids_store = PrefixedStorage::new(POLL_IDS_KEY, storage);
+    TypedStoreMut::attach(&mut ids_store).store(address.0.as_bytes(), &id)?;
+
+    Ok(record)
+}
+
+pub fn read_poll_record<S: ReadonlyStorage>(storage: &S, id: u64) -> StdResult<PollRecord> {
+    let polls_store = ReadonlyPrefixedStorage::new(POLLS_KEY, storage);
+    let polls_store = AppendStore::<PollRecord, _>::attach(&polls_store)
+        .unwrap_or_else(|| Err(StdError::generic_err("no polls were created yet")))?;
+
+    if id >= polls_store.len() as u64 {
+        return Err(StdError::generic_err(format!("no poll with id {}", id)));
+    }
+    polls_store.get_at(id as u32)
+}
+
+/// Reads one page of poll records, along with the number of records that match `status`. Filtered
+/// pages are read from that status' index, so only the requested records are loaded
+pub fn read_poll_records<S: ReadonlyStorage>(
+    storage: &S,
+    status: Option<&PollStatus>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<PollRecord>, u64)> {
+    let page_size = page_size.min(MAX_PAGE_SIZE);
+    let start = page
+        .checked_mul(page_size)
+        .ok_or_else(|| StdError::generic_err("page is out of range"))? as u64;
+
+    let polls_store = ReadonlyPrefixedStorage::new(POLLS_KEY, storage);
+    let polls_store = if let Some(result) = AppendStore::<PollRecord, _>::attach(&polls_store) {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    let status = if let Some(status) = status {
+        status
+    } else {
+        let polls: StdResult<Vec<PollRecord>> = polls_store
+            .iter()
+            .skip(start as usize)
+            .take(page_size as usize)
+            .collect();
+        return Ok((polls?, polls_store.len() as u64));
+    };
+
+    let index_store = ReadonlyPrefixedStorage::multilevel(
+        &[STATUS_INDEX_KEY, status.as_str().as_bytes()],
+        storage,
+    );
+    let index_store = if let Some(result) = AppendStore::<u64, _>::attach(&index_store) {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    let polls: StdResult<Vec<PollRecord>> = index_store
+        .iter()
+        .skip(start as usize)
+        .take(page_size as usize)
+        .map(|id| polls_store.get_at(id? as u32))
+        .collect();
+    Ok((polls?, index_store.len() as u64))
+}
+
+// Poll ids grouped by status, along with each poll's position in its status' index. A poll that
+// leaves a status has its place taken by the last id in that index, so the index stays dense
+fn push_status_index<S: Storage>(storage: &mut S, status: &PollStatus, id: u64) -> StdResult<()> {
+    let mut index_store =
+        PrefixedStorage::multilevel(&[STATUS_INDEX_KEY, status.as_str().as_bytes()], storage);
+    let mut index_store = AppendStoreMut::<u64, _>::attach_or_create(&mut index_store)?;
+    let position = index_store.len();
+    index_store.push(&id)?;
+
+    let mut positions_store = PrefixedStorage::new(STATUS_POSITIONS_KEY, storage);
+    TypedStoreMut::attach(&mut positions_store).store(&id.to_be_bytes(), &position)
+}
+
+fn remove_status_index<S: Storage>(storage: &mut S, status: &PollStatus, id: u64) -> StdResult<()> {
+    let positions_store = ReadonlyPrefixedStorage::new(STATUS_POSITIONS_KEY, storage);
+    let position: u32 = TypedStore::attach(&positions_store).load(&id.to_be_bytes())?;
+
+    let mut index_store =
+        PrefixedStorage::multilevel(&[STATUS_INDEX_KEY, status.as_str().as_bytes()], storage);
+    let mut index_store = AppendStoreMut::<u64, _>::attach_or_create(&mut index_store)?;
+    let last = index_store.pop()?;
+    if last == id {
+        return Ok(());
+    }
+    index_store.set_at(position, &last)?;
+
+    let mut positions_store = PrefixedStorage::new(STATUS_POSITIONS_KEY, storage);
+    TypedStoreMut::attach(&mut positions_store).store(&last.to_be_bytes(), &position)
+}
+
+pub fn read_poll_id<S: ReadonlyStorage>(
+    storage: &S,
+    address: &HumanAddr,
+) -> StdResult<Option<u64>> {
+    let ids_store = ReadonlyPrefixedStorage::new(POLL_IDS_KEY, storage);
+    TypedStore::attach(&ids_store).may_load(address.0.as_bytes())
+}
+
+pub fn update_poll_status<S: Storage>(
+    storage: &mut S,
+    address: &HumanAddr,
+    status: PollStatus,
+) -> StdResult<PollRecord> {
+    let id = read_poll_id(storage, address)?
+        .ok_or_else(|| StdError::generic_err("not a registered poll"))?;
+
+    let mut polls_store = PrefixedStorage::new(POLLS_KEY, storage);
+    let mut polls_store = AppendStoreMut::<PollRecord, _>::attach_or_create(&mut polls_store)?;
+    let mut record = polls_store.get_at(id as u32)?;
+    let prev_status = record.status.clone();
+    record.status = status;
+    polls_store.set_at(id as u32, &record)?;
+
+    if record.status != prev_status {
+        remove_status_index(storage, &prev_status, id)?;
+        push_status_index(storage, &record.status, id)?;
+    }
+
+    Ok(record)
+}
+
+pub fn read_last_poll_time<S: ReadonlyStorage>(
+    storage: &S,
+    author: &HumanAddr,
+) -> StdResult<Option<u64>> {
+    let last_poll_store = ReadonlyPrefixedStorage::new(LAST_POLL_KEY, storage);
+    TypedStore::attach(&last_poll_store).may_load(author.0.as_bytes())
+}
+
+pub fn store_last_poll_time<S: Storage>(
+    storage: &mut S,
+    author: &HumanAddr,
+    time: u64,
+) -> StdResult<()> {
+    let mut last_poll_store = PrefixedStorage::new(LAST_POLL_KEY, storage);
+    TypedStoreMut::attach(&mut last_poll_store).store(author.0.as_bytes(), &time)
+}
+
+// The polls a voter has voted in, only these get the voter's power updates
+pub fn read_voter_polls<S: ReadonlyStorage>(
+    storage: &S,
+    voter: &HumanAddr,
+) -> StdResult<Vec<ActivePoll>> {
+    let voter_polls_store = ReadonlyPrefixedStorage::new(VOTER_POLLS_KEY, storage);
+    Ok(TypedStore::attach(&voter_polls_store)
+        .may_load(voter.0.as_bytes())?
+        .unwrap_or_default())
+}
+
+pub fn store_voter_polls<S: Storage>(
+    storage: &mut S,
+    voter: &HumanAddr,
+    polls: &[ActivePoll],
+) -> StdResult<()> {
+    let mut voter_polls_store = PrefixedStorage::new(VOTER_POLLS_KEY, storage);
+    let mut voter_polls_store = TypedStoreMut::<Vec<ActivePoll>, _>::attach(&mut voter_polls_store);
+    if polls.is_empty() {
+        voter_polls_store.remove(voter.0.as_bytes());
+        Ok(())
+    } else {
+        voter_polls_store.store(voter.0.as_bytes(), &polls.to_vec())
+    }
+}
+
+pub fn read_template<S: ReadonlyStorage>(storage: &S, name: &str) -> StdResult<PollTemplate> {
+    let templates_store = ReadonlyPrefixedStorage::new(TEMPLATES_KEY, storage);
+    TypedStore::attach(&templates_store)
+        .may_load(name.as_bytes())?
+        .ok_or_else(|| StdError::generic_err(format!("no template named {}", name)))
+}
+
+pub fn read_templates<S: ReadonlyStorage>(storage: &S) -> StdResult<Vec<(String, PollTemplate)>> {
+    let names: Vec<String> = TypedStore::attach(storage)
+        .may_load(TEMPLATE_NAMES_KEY)?
+        .unwrap_or_default();
+    names
+        .into_iter()
+        .map(|name| {
+            let template = read_template(storage, &name)?;
+            Ok((name, template))
+        })
+        .collect()
+}
+
+pub fn store_template<S: Storage>(
+    storage: &mut S,
+    name: &str,
+    template: Option<&PollTemplate>,
+) -> StdResult<()> {
+    let mut names: Vec<String> = TypedStore::attach(storage)
+        .may_load(TEMPLATE_NAMES_KEY)?
+        .unwrap_or_default();
+    names.retain(|n| n != name);
+
+    let mut templates_store = PrefixedStorage::new(TEMPLATES_KEY, storage);
+    let mut templates_store = TypedStoreMut::<PollTemplate, _>::attach(&mut templates_store);
+    match template {
+        Some(template) => {
+            templates_store.store(name.as_bytes(), template)?;
+            names.push(name.to_string());
+        }
+        None => templates_store.remove(name.as_bytes()),
+    }
+
+    TypedStoreMut::attach(storage).store(TEMPLATE_NAMES_KEY, &names)
+}
+
+pub fn read_pending_poll<S: ReadonlyStorage>(storage: &S, id: u64) -> StdResult<PendingPoll> {
+    let pending_store = ReadonlyPrefixedStorage::new(PENDING_POLLS_KEY, storage);
+    TypedStore::attach(&pending_store)
+        .may_load(&id.to_be_bytes())?
+        .ok_or_else(|| StdError::generic_err(format!("no pending poll with id {}", id)))
+}
+
+pub fn store_pending_poll<S: Storage>(
+    storage: &mut S,
+    id: u64,
+    pending: Option<&PendingPoll>,
+) -> StdResult<()> {
+    let mut pending_store = PrefixedStorage::new(PENDING_POLLS_KEY, storage);
+    let mut pending_store = TypedStoreMut::<PendingPoll, _>::attach(&mut pending_store);
+    match pending {
+        Some(pending) => pending_store.store(&id.to_be_bytes(), pending),
+        None => {
+            pending_store.remove(&id.to_be_bytes());
+            Ok(())
+        }
+    }
 }
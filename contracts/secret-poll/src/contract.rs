@@ -1,19 +1,28 @@
-use crate::msg::{FinalizeAnswer, QueryAnswer, QueryMsg, ResponseStatus};
-use crate::querier::query_staking_balance;
+use crate::msg::{FinalizeAnswer, QueryAnswer, QueryMsg, ResponseStatus, TallyResult, VoteReason};
+use crate::querier::{query_staking_balance, query_voting_power, weighted_power};
 use crate::state::{
-    read_vote, store_vote, StoredPollConfig, StoredRevealConfig, Vote, CONFIG_KEY, METADATA_KEY,
-    NUM_OF_VOTERS_KEY, OWNER_KEY, REVEAL_CONFIG, STAKING_POOL_KEY, TALLY_KEY,
+    push_rolling_hash, read_commitment, read_hash_history, read_power_sources, read_rankings,
+    read_reasons, read_receipt, read_vote, remove_commitment, store_commitment,
+    store_power_sources, store_reason, store_vote, update_ranking, Commitment, StoredPollConfig,
+    StoredReason, StoredRevealConfig, Vote, CONFIG_KEY, EXTRA_POOLS_KEY, FINAL_STAKED_KEY,
+    MAX_REASON_LENGTH, METADATA_KEY, NUM_OF_COMMITMENTS_KEY, NUM_OF_VOTERS_KEY, OWNER_HASH_KEY,
+    OWNER_KEY, PRNG_SEED_KEY, REVEAL_CONFIG, STAKING_POOL_KEY, TALLY_KEY, TOTAL_POWER_KEY,
+    TURNOUT_SCALE, VOTER_COUNTS_KEY, VOTE_SPLIT_SCALE,
 };
 use cosmwasm_std::{
     log, to_binary, Api, Binary, CosmosMsg, Env, Extern, HandleResponse, HumanAddr, InitResponse,
-    Querier, StdError, StdResult, Storage, Uint128, WasmMsg,
+    LogAttribute, Querier, StdError, StdResult, Storage, Uint128, WasmMsg,
+};
+use scrt_finance::math::mul_div;
+use scrt_finance::secret_vote_types::{
+    log_actions, log_keys, Ballot, PollFactoryHandleMsg, PollHandleMsg, PollInitMsg, PollMetadata,
+    PollOutcome, VoteType, WeightedPool,
 };
-use scrt_finance::secret_vote_types::{PollHandleMsg, PollInitMsg, PollMetadata};
 use scrt_finance::types::SecretContract;
-use secret_toolkit::snip20;
 use secret_toolkit::snip20::{balance_query, Balance};
 use secret_toolkit::storage::{TypedStore, TypedStoreMut};
 use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
 use std::mem::size_of;
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
@@ -24,12 +33,20 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     let owner = env.message.sender;
     TypedStoreMut::attach(&mut deps.storage).store(OWNER_KEY, &owner)?; // This is in fact the factory contract
     TypedStoreMut::attach(&mut deps.storage).store(STAKING_POOL_KEY, &msg.staking_pool)?;
+    TypedStoreMut::attach(&mut deps.storage).store(EXTRA_POOLS_KEY, &msg.extra_pools)?;
 
     if msg.choices.len() < 2 {
         return Err(StdError::generic_err(
             "you have to provide at least two choices",
         ));
     }
+    if msg.choices.len() > u8::MAX as usize
+        || (msg.metadata.vote_type == VoteType::Approval && msg.choices.len() > 64)
+    {
+        return Err(StdError::generic_err(
+            "too many choices for this type of vote",
+        ));
+    }
 
     // Sanity checks to prevent starting a new poll by mistake
     if msg.metadata.title.len() < 2 {
@@ -49,35 +66,104 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     }
     TypedStoreMut::attach(&mut deps.storage).store(METADATA_KEY, &msg.metadata)?;
 
+    if msg.config.quorum > 100 || msg.config.min_threshold > 100 {
+        return Err(StdError::generic_err(
+            "quorum and minimum threshold must be percentages between 0 and 100",
+        ));
+    }
+    if msg.config.pass_choice as usize >= msg.choices.len() {
+        return Err(StdError::generic_err(format!(
+            "pass choice {} does not exist in this poll",
+            msg.config.pass_choice
+        )));
+    }
+
     let tally: Vec<u128> = vec![0; msg.choices.len()];
     TypedStoreMut::attach(&mut deps.storage).store(TALLY_KEY, &tally)?;
-
-    let ending = env.block.time + msg.config.duration;
+    TypedStoreMut::attach(&mut deps.storage).store(VOTER_COUNTS_KEY, &vec![0_u64; tally.len()])?;
+    TypedStoreMut::attach(&mut deps.storage).store(TOTAL_POWER_KEY, &0_u128)?;
+
+    let too_long = || StdError::generic_err("voting delay and duration are too long");
+    let starting = env
+        .block
+        .time
+        .checked_add(msg.config.voting_delay.unwrap_or(0))
+        .ok_or_else(too_long)?;
+    let ending = starting
+        .checked_add(msg.config.duration)
+        .ok_or_else(too_long)?;
+    let reveal_end_timestamp = match msg.config.reveal_period {
+        Some(period) => Some(
+            ending
+                .checked_add(period)
+                .ok_or_else(|| StdError::generic_err("reveal period is too long"))?,
+        ),
+        None => None,
+    };
     TypedStoreMut::attach(&mut deps.storage).store(
         CONFIG_KEY,
         &StoredPollConfig {
+            start_timestamp: starting,
             end_timestamp: ending,
+            reveal_end_timestamp,
             quorum: msg.config.quorum,
             min_threshold: msg.config.min_threshold,
+            quorum_inclusive: msg.config.quorum_inclusive,
+            threshold_inclusive: msg.config.threshold_inclusive,
             choices: msg.choices,
             finalized: false,
             valid: false,
+            outcome: None,
             rolling_hash: [0u8; 32],
+            cancelled: false,
+            // The block before the poll's, so stake added in the same block doesn't count
+            snapshot_block: if msg.config.snapshot_voting {
+                Some(env.block.height.saturating_sub(1))
+            } else {
+                None
+            },
+            pass_choice: msg.config.pass_choice,
         },
     )?;
 
+    if msg.reveal_com.fallback_n.unwrap_or(0) > msg.reveal_com.n {
+        return Err(StdError::generic_err(
+            "fallback quorum can't be larger than the reveal committee quorum",
+        ));
+    }
+
+    // The timeout starts when votes can no longer be cast or revealed
+    let last_action = reveal_end_timestamp.unwrap_or(ending);
+    let deadline = match msg.reveal_com.timeout {
+        Some(timeout) => Some(
+            last_action
+                .checked_add(timeout)
+                .ok_or_else(|| StdError::generic_err("reveal committee timeout is too long"))?,
+        ),
+        None => None,
+    };
+
+    let prng_seed: [u8; 32] = Sha256::digest(&msg.prng_seed.0).into();
+    TypedStoreMut::attach(&mut deps.storage).store(PRNG_SEED_KEY, &prng_seed)?;
+
     TypedStoreMut::attach(&mut deps.storage).store(NUM_OF_VOTERS_KEY, &(0_u64))?;
+    TypedStoreMut::attach(&mut deps.storage).store(NUM_OF_COMMITMENTS_KEY, &(0_u64))?;
     TypedStoreMut::attach(&mut deps.storage).store(
         REVEAL_CONFIG,
         &StoredRevealConfig {
             committee: msg.reveal_com,
             num_revealed: 0,
             revealed: vec![],
+            deadline,
+            fallback_used: false,
+            absent: vec![],
         },
     )?;
 
     let mut messages = vec![];
     if let Some(init_hook) = msg.init_hook {
+        // The init hook is always sent to the factory, which needs its hash for executing the poll
+        TypedStoreMut::attach(&mut deps.storage).store(OWNER_HASH_KEY, &init_hook.code_hash)?;
         messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: init_hook.contract_addr,
             callback_code_hash: init_hook.code_hash,
@@ -99,14 +185,33 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
 ) -> StdResult<HandleResponse> {
     match msg {
         PollHandleMsg::Vote {
-            choice,
+            ballot,
             staking_pool_viewing_key,
             salt,
-        } => vote(deps, env, choice, staking_pool_viewing_key, salt),
-        PollHandleMsg::UpdateVotingPower { voter, new_power } => {
-            update_voting_power(deps, env, voter, new_power.u128())
-        }
+            reason,
+            disclose_voter,
+        } => vote(
+            deps,
+            env,
+            ballot,
+            staking_pool_viewing_key,
+            salt,
+            reason,
+            disclose_voter.unwrap_or(false),
+        ),
+        PollHandleMsg::CommitVote {
+            commitment,
+            staking_pool_viewing_key,
+        } => commit_vote(deps, env, commitment, staking_pool_viewing_key),
+        PollHandleMsg::RevealVote { ballot, salt } => reveal_vote(deps, env, ballot, salt),
+        PollHandleMsg::UpdateVotingPower {
+            voter,
+            new_power,
+            pool,
+        } => update_voting_power(deps, env, voter, new_power.u128(), pool),
         PollHandleMsg::Finalize { rolling_hash } => finalize(deps, env, rolling_hash),
+        PollHandleMsg::Execute {} => execute(deps, env),
+        PollHandleMsg::Cancel {} => cancel(deps, env),
     }
 }
 
@@ -118,12 +223,18 @@ pub fn query<S: Storage, A: Api, Q: Querier>(
         QueryMsg::Choices {} => query_choices(deps),
         QueryMsg::HasVoted { voter } => query_has_voted(deps, voter),
         QueryMsg::Tally {} => query_tally(deps),
+        QueryMsg::Results {} => query_results(deps),
         QueryMsg::Vote { voter, key } => query_vote(deps, voter, key),
         QueryMsg::NumberOfVoters {} => query_num_of_voters(deps),
         QueryMsg::VoteInfo {} => query_vote_info(deps),
         QueryMsg::RevealCommittee {} => query_reveal_com(deps),
         QueryMsg::Revealed {} => query_revealed(deps),
         QueryMsg::RollingHash {} => query_rolling_hash(deps),
+        QueryMsg::Reasons { page, page_size } => query_reasons(deps, page, page_size),
+        QueryMsg::RollingHashHistory { page, page_size } => {
+            query_rolling_hash_history(deps, page, page_size)
+        }
+        QueryMsg::VoteReceipt { voter, key } => query_vote_receipt(deps, voter, key),
     }
 }
 
@@ -132,32 +243,60 @@ pub fn query<S: Storage, A: Api, Q: Querier>(
 pub fn vote<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    choice: u8,
+    ballot: Ballot,
     key: String,
     salt: String,
+    reason: Option<String>,
+    disclose_voter: bool,
 ) -> StdResult<HandleResponse> {
-    let mut config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    let mut config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
     require_vote_ongoing(&env, &config)?;
+    if config.reveal_end_timestamp.is_some() {
+        return Err(StdError::generic_err(
+            "this poll only accepts committed votes",
+        ));
+    }
 
-    let staking_pool: SecretContract = TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
-    let voting_power = snip20::balance_query(
-        &deps.querier,
-        env.message.sender.clone(),
-        key,
-        256,
-        staking_pool.contract_hash,
-        staking_pool.address,
-    )?
-    .amount
-    .u128();
+    let metadata: PollMetadata = TypedStore::attach(&deps.storage).load(METADATA_KEY)?;
+    validate_ballot(&metadata.vote_type, &ballot, config.choices.len())?;
+
+    if let Some(reason) = reason {
+        if reason.len() > MAX_REASON_LENGTH {
+            return Err(StdError::generic_err(format!(
+                "reason can't be longer than {} bytes",
+                MAX_REASON_LENGTH
+            )));
+        }
+
+        // Voting again without a reason keeps the previous one. Salts end up published with the
+        // rolling hash, so the shuffle is seeded from a secret only the poll knows instead
+        let prng_seed: [u8; 32] = TypedStore::attach(&deps.storage).load(PRNG_SEED_KEY)?;
+        let entropy = reason_entropy(&prng_seed, &env.message.sender);
+        store_reason(
+            deps,
+            StoredReason {
+                voter: env.message.sender.clone(),
+                reason,
+                disclose_voter,
+            },
+            &entropy,
+        )?;
+    }
+
+    // Includes any voting power that was delegated to the voter
+    let powers = query_voting_power(deps, env.message.sender.clone(), key, config.snapshot_block)?;
+    let extra_pools: Vec<WeightedPool> = TypedStore::attach(&deps.storage).load(EXTRA_POOLS_KEY)?;
+    let voting_power = weighted_power(&extra_pools, &powers)?;
+    store_power_sources(deps, &env.message.sender, &powers)?;
 
     let prev_vote = read_vote(deps, &env.message.sender).ok();
+    let first_vote = prev_vote.is_none();
     update_vote(
         deps,
         &env.message.sender,
         prev_vote,
         Vote {
-            choice,
+            ballot: ballot.clone(),
             voting_power,
         },
     )?;
@@ -166,17 +305,116 @@ pub fn vote<S: Storage, A: Api, Q: Querier>(
         config.rolling_hash,
         &env.message.sender,
         Vote {
-            choice,
+            ballot,
             voting_power,
         },
         salt,
     );
     config.rolling_hash = new_hash;
     TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
+    push_rolling_hash(deps, &env.message.sender, new_hash)?;
+
+    // The factory only forwards voting power updates to polls the voter has voted in. Snapshot
+    // polls don't take updates, so they never register voters
+    let mut messages = vec![];
+    let owner_hash: Option<String> = TypedStore::attach(&deps.storage).may_load(OWNER_HASH_KEY)?;
+    let live_power = config.snapshot_block.is_none();
+    if let (true, true, Some(owner_hash)) = (first_vote, live_power, owner_hash) {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: TypedStore::attach(&deps.storage).load(OWNER_KEY)?,
+            callback_code_hash: owner_hash,
+            msg: to_binary(&PollFactoryHandleMsg::RegisterVoter {
+                voter: env.message.sender.clone(),
+            })?,
+            send: vec![],
+        }));
+    }
+
+    Ok(HandleResponse {
+        messages,
+        log: voter_log(log_actions::VOTE, &env, &env.message.sender),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+pub fn commit_vote<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    commitment: Binary,
+    key: String,
+) -> StdResult<HandleResponse> {
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    require_vote_ongoing(&env, &config)?;
+    if config.reveal_end_timestamp.is_none() {
+        return Err(StdError::generic_err(
+            "this poll doesn't use commit-reveal voting",
+        ));
+    }
+
+    if commitment.len() != 32 {
+        return Err(StdError::generic_err("commitment must be a sha256 hash"));
+    }
+
+    // Power counts as of the commit, and committing again replaces the previous commitment
+    let powers = query_voting_power(deps, env.message.sender.clone(), key, config.snapshot_block)?;
+    let extra_pools: Vec<WeightedPool> = TypedStore::attach(&deps.storage).load(EXTRA_POOLS_KEY)?;
+    let voting_power = weighted_power(&extra_pools, &powers)?;
+    if read_commitment(deps, &env.message.sender)?.is_none() {
+        let mut commitments_store = TypedStoreMut::<u64, S>::attach(&mut deps.storage);
+        let num_of_commitments = commitments_store.load(NUM_OF_COMMITMENTS_KEY)?;
+        commitments_store.store(NUM_OF_COMMITMENTS_KEY, &(num_of_commitments + 1))?;
+    }
+    store_commitment(
+        deps,
+        &env.message.sender,
+        &Commitment {
+            hash: commitment.0,
+            voting_power,
+        },
+    )?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: voter_log(log_actions::COMMIT_VOTE, &env, &env.message.sender),
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+pub fn reveal_vote<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ballot: Ballot,
+    salt: String,
+) -> StdResult<HandleResponse> {
+    let mut config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    require_reveal_ongoing(&env, &config)?;
+
+    let commitment = read_commitment(deps, &env.message.sender)?
+        .ok_or_else(|| StdError::generic_err("no committed vote to reveal"))?;
+    if commitment_hash(&env.message.sender, &ballot, &salt).to_vec() != commitment.hash {
+        return Err(StdError::generic_err(
+            "ballot and salt don't match the commitment",
+        ));
+    }
+
+    let metadata: PollMetadata = TypedStore::attach(&deps.storage).load(METADATA_KEY)?;
+    validate_ballot(&metadata.vote_type, &ballot, config.choices.len())?;
+
+    // Each commitment can only be revealed once, so this is always a new vote
+    remove_commitment(deps, &env.message.sender);
+    let vote = Vote {
+        ballot,
+        voting_power: commitment.voting_power,
+    };
+    update_vote(deps, &env.message.sender, None, vote.clone())?;
+
+    config.rolling_hash = roll_hash(config.rolling_hash, &env.message.sender, vote, salt);
+    TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
+    push_rolling_hash(deps, &env.message.sender, config.rolling_hash)?;
 
     Ok(HandleResponse {
         messages: vec![],
-        log: vec![log("voted", env.message.sender.to_string())],
+        log: voter_log(log_actions::REVEAL_VOTE, &env, &env.message.sender),
         data: Some(to_binary(&ResponseStatus::Success)?),
     })
 }
@@ -186,28 +424,59 @@ pub fn update_voting_power<S: Storage, A: Api, Q: Querier>(
     env: Env,
     voter: HumanAddr,
     new_power: u128,
+    pool: Option<HumanAddr>,
 ) -> StdResult<HandleResponse> {
-    let config = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
-    require_vote_ongoing(&env, &config)?;
-
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
     let owner: HumanAddr = TypedStore::attach(&deps.storage).load(OWNER_KEY)?;
     if env.message.sender != owner {
         return Err(StdError::unauthorized());
     }
 
+    // The factory may still have this poll in its voters' lists, failing would block staking.
+    // Snapshot polls keep the power voters had when the poll was created
+    if config.cancelled || config.snapshot_block.is_some() {
+        return Ok(HandleResponse::default());
+    }
+    require_vote_ongoing(&env, &config)?;
+
+    // Committed votes aren't stored as votes yet, they keep the power they had when committed
     let mut logs = vec![];
     if let Ok(prev_vote) = read_vote(deps, &voter) {
+        // Pools that were added to the factory after this poll was created don't count here
+        let staking_pool: SecretContract =
+            TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
+        let extra_pools: Vec<WeightedPool> =
+            TypedStore::attach(&deps.storage).load(EXTRA_POOLS_KEY)?;
+        let index = match pool {
+            None => Some(0),
+            Some(pool) if pool == staking_pool.address => Some(0),
+            Some(pool) => extra_pools
+                .iter()
+                .position(|p| p.pool.address == pool)
+                .map(|i| i + 1),
+        };
+        let index = match index {
+            Some(index) => index,
+            None => return Ok(HandleResponse::default()),
+        };
+
+        let mut powers =
+            read_power_sources(deps, &voter)?.unwrap_or_else(|| vec![prev_vote.voting_power]);
+        powers.resize(extra_pools.len() + 1, 0);
+        powers[index] = new_power;
+        store_power_sources(deps, &voter, &powers)?;
+
         update_vote(
             deps,
             &voter,
             Some(prev_vote.clone()),
             Vote {
-                choice: prev_vote.choice,
-                voting_power: new_power,
+                ballot: prev_vote.ballot,
+                voting_power: weighted_power(&extra_pools, &powers)?,
             },
         )?;
 
-        logs.push(log("voting_power_updated", voter.to_string()));
+        logs = voter_log(log_actions::UPDATE_VOTING_POWER, &env, &voter);
     }
 
     Ok(HandleResponse {
@@ -223,9 +492,17 @@ pub fn finalize<S: Storage, A: Api, Q: Querier>(
     rolling_hash: String,
 ) -> StdResult<HandleResponse> {
     let mut config: StoredPollConfig = TypedStoreMut::attach(&mut deps.storage).load(CONFIG_KEY)?;
+    if config.cancelled {
+        return Err(StdError::generic_err("poll was cancelled"));
+    }
     if env.block.time < config.end_timestamp {
         return Err(StdError::generic_err("vote has not ended yet"));
     }
+    if let Some(reveal_end) = config.reveal_end_timestamp {
+        if env.block.time <= reveal_end {
+            return Err(StdError::generic_err("reveal period has not ended yet"));
+        }
+    }
 
     if hex::encode(&config.rolling_hash) != rolling_hash {
         return Err(StdError::generic_err("incorrect rolling hash"));
@@ -233,11 +510,14 @@ pub fn finalize<S: Storage, A: Api, Q: Querier>(
 
     let mut reveal_conf_store = TypedStoreMut::attach(&mut deps.storage);
     let mut reveal_conf: StoredRevealConfig = reveal_conf_store.load(REVEAL_CONFIG)?;
-    if !reveal_conf
+    let is_revealer = reveal_conf
         .committee
         .revealers
-        .contains(&env.message.sender)
-    {
+        .contains(&env.message.sender);
+    let required = required_reveals(&reveal_conf, env.block.time);
+
+    // Once the deadline passes without a fallback quorum, anyone can finalize
+    if !is_revealer && required > 0 {
         return Err(StdError::unauthorized());
     }
 
@@ -245,31 +525,50 @@ pub fn finalize<S: Storage, A: Api, Q: Querier>(
         return Err(StdError::generic_err("already finalized the vote"));
     }
 
-    reveal_conf.revealed.push(env.message.sender);
-    reveal_conf.num_revealed += 1;
+    if is_revealer {
+        reveal_conf.revealed.push(env.message.sender);
+        reveal_conf.num_revealed += 1;
+    }
+
+    let finalizing = !config.finalized && reveal_conf.num_revealed >= required;
+    if finalizing && required < reveal_conf.committee.n {
+        reveal_conf.fallback_used = true;
+        reveal_conf.absent = reveal_conf
+            .committee
+            .revealers
+            .iter()
+            .filter(|revealer| !reveal_conf.revealed.contains(revealer))
+            .cloned()
+            .collect();
+    }
     reveal_conf_store.store(REVEAL_CONFIG, &reveal_conf)?;
 
-    if reveal_conf.num_revealed > reveal_conf.committee.n {
+    if config.finalized {
         let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY)?; // Already revealed
+        let (result, _) = tally_result(deps, &config, &tally)?;
         return Ok(HandleResponse {
             messages: vec![],
             log: vec![],
             data: Some(to_binary(&FinalizeAnswer {
                 finalized: config.finalized,
                 valid: Some(config.valid),
+                outcome: config.outcome,
                 choices: Some(config.choices),
                 tally: Some(tally.iter().map(|c| Uint128(*c)).collect()),
+                result: Some(result),
             })?),
         });
-    } else if reveal_conf.num_revealed < reveal_conf.committee.n {
+    } else if !finalizing {
         return Ok(HandleResponse {
             messages: vec![],
             log: vec![],
             data: Some(to_binary(&FinalizeAnswer {
                 finalized: false,
                 valid: None,
+                outcome: None,
                 choices: None,
                 tally: None,
+                result: None,
             })?),
         });
     }
@@ -277,33 +576,145 @@ pub fn finalize<S: Storage, A: Api, Q: Querier>(
     config.finalized = true;
 
     let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY)?;
+    let total_power: u128 = TypedStore::attach(&deps.storage).load(TOTAL_POWER_KEY)?;
+    let (result, winning_power) = tally_result(deps, &config, &tally)?;
 
     // Validation tests
-    let sefi_balance = query_staking_balance(deps)?;
-    let total_vote_count: u128 = tally.iter().sum();
-    let participation = 100 * total_vote_count / sefi_balance; // This should give a percentage integer X/100%
-    if participation > config.quorum as u128 {
-        config.valid = true;
-    }
-    if let Some(winning_choice) = tally.iter().max() {
-        config.valid = config.valid && (*winning_choice > config.min_threshold as u128)
-    } else {
-        return Err(StdError::generic_err("storage is corrupted")); // iter().max() returns `None` only when the Vec is empty
-    }
+    let staked_balance = query_staking_balance(deps, config.snapshot_block)?;
+    let outcome = poll_outcome(
+        &config,
+        total_power,
+        result.winner(),
+        winning_power,
+        staked_balance,
+    )?;
+    config.valid = outcome == PollOutcome::Passed;
+    config.outcome = Some(outcome.clone());
 
     TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
+    TypedStoreMut::attach(&mut deps.storage).store(FINAL_STAKED_KEY, &staked_balance)?;
+
+    // Let the factory record the final status of the poll
+    let mut messages = vec![];
+    let owner_hash: Option<String> = TypedStore::attach(&deps.storage).may_load(OWNER_HASH_KEY)?;
+    if let Some(owner_hash) = owner_hash {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: TypedStore::attach(&deps.storage).load(OWNER_KEY)?,
+            callback_code_hash: owner_hash,
+            msg: to_binary(&PollFactoryHandleMsg::PollFinalized {
+                outcome: outcome.clone(),
+            })?,
+            send: vec![],
+        }));
+    }
+
     Ok(HandleResponse {
-        messages: vec![],
-        log: vec![],
+        messages,
+        log: vec![
+            log(log_keys::ACTION, log_actions::FINALIZE),
+            log(log_keys::POLL_ADDR, &env.contract.address),
+            log(log_keys::STATUS, outcome.as_str()),
+        ],
         data: Some(to_binary(&FinalizeAnswer {
             finalized: config.finalized,
             valid: Some(config.valid),
+            outcome: Some(outcome),
             choices: Some(config.choices),
             tally: Some(tally.iter().map(|c| Uint128(*c)).collect()),
+            result: Some(result),
         })?),
     })
 }
 
+pub fn execute<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    require_vote_finalized_and_valid(&config)?;
+
+    let owner: HumanAddr = TypedStore::attach(&deps.storage).load(OWNER_KEY)?;
+    let owner_hash: String = TypedStore::attach(&deps.storage)
+        .may_load(OWNER_HASH_KEY)?
+        .ok_or_else(|| StdError::generic_err("poll was not created by a factory"))?;
+
+    // The factory makes sure that the messages are only executed once
+    Ok(HandleResponse {
+        messages: vec![CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: owner,
+            callback_code_hash: owner_hash,
+            msg: to_binary(&PollFactoryHandleMsg::ExecutePoll {})?,
+            send: vec![],
+        })],
+        log: vec![
+            log(log_keys::ACTION, log_actions::EXECUTE),
+            log(log_keys::POLL_ADDR, env.contract.address),
+        ],
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
+pub fn cancel<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let mut config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if config.finalized {
+        return Err(StdError::generic_err("poll was already finalized"));
+    } else if config.cancelled {
+        return Err(StdError::generic_err("poll was already cancelled"));
+    }
+
+    // The owner can cancel at any time, the author only while voting is open and nobody voted
+    let owner: HumanAddr = TypedStore::attach(&deps.storage).load(OWNER_KEY)?;
+    let by_owner = env.message.sender == owner;
+    if !by_owner {
+        let metadata: PollMetadata = TypedStore::attach(&deps.storage).load(METADATA_KEY)?;
+        if metadata.author_addr.as_ref() != Some(&env.message.sender) {
+            return Err(StdError::unauthorized());
+        }
+
+        let num_of_voters: u64 = TypedStore::attach(&deps.storage).load(NUM_OF_VOTERS_KEY)?;
+        let num_of_commitments: u64 =
+            TypedStore::attach(&deps.storage).load(NUM_OF_COMMITMENTS_KEY)?;
+        if num_of_voters > 0 || num_of_commitments > 0 {
+            return Err(StdError::generic_err(
+                "poll can't be cancelled by its author after votes were cast",
+            ));
+        }
+        if config.end_timestamp < env.block.time {
+            return Err(StdError::generic_err(
+                "poll can't be cancelled by its author after voting ended",
+            ));
+        }
+    }
+
+    config.cancelled = true;
+    TypedStoreMut::attach(&mut deps.storage).store(CONFIG_KEY, &config)?;
+
+    // A cancellation by the factory is already known to it
+    let mut messages = vec![];
+    let owner_hash: Option<String> = TypedStore::attach(&deps.storage).may_load(OWNER_HASH_KEY)?;
+    if let (false, Some(owner_hash)) = (by_owner, owner_hash) {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: owner,
+            callback_code_hash: owner_hash,
+            msg: to_binary(&PollFactoryHandleMsg::PollCancelled {})?,
+            send: vec![],
+        }));
+    }
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![
+            log(log_keys::ACTION, log_actions::CANCEL),
+            log(log_keys::POLL_ADDR, env.contract.address),
+            log(log_keys::STATUS, "cancelled"),
+        ],
+        data: Some(to_binary(&ResponseStatus::Success)?),
+    })
+}
+
 // Query
 
 pub fn query_choices<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
@@ -330,7 +741,7 @@ pub fn query_has_voted<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     voter: HumanAddr,
 ) -> StdResult<Binary> {
-    let has_voted = read_vote(deps, &voter).is_ok();
+    let has_voted = read_vote(deps, &voter).is_ok() || read_commitment(deps, &voter)?.is_some();
     Ok(to_binary(&QueryAnswer::HasVoted { has_voted })?)
 }
 
@@ -347,24 +758,48 @@ pub fn query_tally<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> St
     })?)
 }
 
+pub fn query_results<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<Binary> {
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if !config.finalized {
+        return Err(StdError::generic_err("vote hasn't been finalized yet"));
+    }
+
+    let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY)?;
+    let voters_per_choice: Vec<u64> = TypedStore::attach(&deps.storage).load(VOTER_COUNTS_KEY)?;
+    let num_of_voters: u64 = TypedStore::attach(&deps.storage).load(NUM_OF_VOTERS_KEY)?;
+    let total_power: u128 = TypedStore::attach(&deps.storage).load(TOTAL_POWER_KEY)?;
+    let total_staked: u128 = TypedStore::attach(&deps.storage).load(FINAL_STAKED_KEY)?;
+
+    let turnout = if total_staked == 0 {
+        0
+    } else {
+        mul_div(total_power, TURNOUT_SCALE, total_staked)?
+            .0
+            .min(TURNOUT_SCALE) // Voting power can exceed the final stake if voters unstaked
+    };
+
+    Ok(to_binary(&QueryAnswer::Results {
+        choices: config.choices,
+        tally: tally.iter().map(|c| Uint128(*c)).collect(),
+        voters_per_choice,
+        num_of_voters,
+        total_power: Uint128(total_power),
+        total_staked: Uint128(total_staked),
+        turnout: turnout as u32,
+        outcome: config.outcome,
+    })?)
+}
+
 pub fn query_vote<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     voter: HumanAddr,
     key: String,
 ) -> StdResult<Binary> {
-    let staking_pool: SecretContract = TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
-    let _balance: Balance = balance_query(
-        &deps.querier,
-        voter.clone(),
-        key,
-        256,
-        staking_pool.contract_hash,
-        staking_pool.address,
-    )?; // Balance doesn't matter, we're just verifying the viewing key
+    authenticate_voter(deps, voter.clone(), key)?;
 
     let vote: Vote = TypedStore::attach(&deps.storage).load(voter.0.as_bytes())?;
     Ok(to_binary(&QueryAnswer::Vote {
-        choice: vote.choice,
+        ballot: vote.ballot,
         voting_power: Uint128(vote.voting_power),
     })?)
 }
@@ -398,6 +833,10 @@ pub fn query_revealed<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) ->
         required: reveal_config.committee.n,
         num_revealed: reveal_config.num_revealed,
         revealed: reveal_config.revealed,
+        deadline: reveal_config.deadline,
+        fallback_required: reveal_config.committee.fallback_n,
+        fallback_used: reveal_config.fallback_used,
+        absent: reveal_config.absent,
     })?)
 }
 
@@ -412,8 +851,99 @@ pub fn query_rolling_hash<S: Storage, A: Api, Q: Querier>(
     })?)
 }
 
+/// Reasons are listed in a shuffled order. Commit-reveal polls don't take reasons, so theirs is
+/// always empty
+pub fn query_reasons<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<Binary> {
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if !config.finalized {
+        return Err(StdError::generic_err("vote hasn't been finalized yet"));
+    }
+
+    let (stored_reasons, total) = read_reasons(deps, page, page_size)?;
+    let mut reasons = Vec::with_capacity(stored_reasons.len());
+    for stored_reason in stored_reasons {
+        let vote = read_vote(deps, &stored_reason.voter)?;
+        reasons.push(VoteReason {
+            voter: if stored_reason.disclose_voter {
+                Some(stored_reason.voter)
+            } else {
+                None
+            },
+            ballot: vote.ballot,
+            reason: stored_reason.reason,
+        });
+    }
+
+    Ok(to_binary(&QueryAnswer::Reasons { reasons, total })?)
+}
+
+pub fn query_vote_receipt<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: HumanAddr,
+    key: String,
+) -> StdResult<Binary> {
+    authenticate_voter(deps, voter.clone(), key)?;
+
+    let (position, hash) = read_receipt(deps, &voter)?
+        .ok_or_else(|| StdError::generic_err("no vote was included for this voter"))?;
+
+    Ok(to_binary(&QueryAnswer::VoteReceipt {
+        position,
+        rolling_hash: hex::encode(&hash),
+    })?)
+}
+
+pub fn query_rolling_hash_history<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<Binary> {
+    let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY)?;
+    if !config.finalized {
+        return Err(StdError::generic_err("vote hasn't been finalized yet"));
+    }
+
+    let (hashes, total) = read_hash_history(deps, page, page_size)?;
+
+    Ok(to_binary(&QueryAnswer::RollingHashHistory {
+        hashes: hashes.iter().map(hex::encode).collect(),
+        total,
+    })?)
+}
+
 // Helper functions
 
+/// Logs who acted on the poll, never what they voted for
+fn voter_log(action: &str, env: &Env, voter: &HumanAddr) -> Vec<LogAttribute> {
+    vec![
+        log(log_keys::ACTION, action),
+        log(log_keys::POLL_ADDR, &env.contract.address),
+        log(log_keys::VOTER, voter),
+    ]
+}
+
+fn authenticate_voter<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: HumanAddr,
+    key: String,
+) -> StdResult<()> {
+    let staking_pool: SecretContract = TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
+    let _balance: Balance = balance_query(
+        &deps.querier,
+        voter,
+        key,
+        256,
+        staking_pool.contract_hash,
+        staking_pool.address,
+    )?; // Balance doesn't matter, we're just verifying the viewing key
+
+    Ok(())
+}
+
 fn update_vote<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     voter: &HumanAddr,
@@ -421,17 +951,16 @@ fn update_vote<S: Storage, A: Api, Q: Querier>(
     new_vote: Vote,
 ) -> StdResult<()> {
     let mut tally: Vec<u128> = TypedStoreMut::attach(&mut deps.storage).load(TALLY_KEY)?;
+    let mut total_power: u128 = TypedStoreMut::attach(&mut deps.storage).load(TOTAL_POWER_KEY)?;
+    let mut voter_counts: Vec<u64> =
+        TypedStoreMut::attach(&mut deps.storage).load(VOTER_COUNTS_KEY)?;
 
     if let Some(previous_vote) = previous_vote {
-        if let Some(choice_tally) = tally.get_mut(previous_vote.choice as usize) {
-            *choice_tally -= previous_vote.voting_power; // Can't underflow, `choice_tally` >= `old_vote.voting_power`
-        } else {
-            // Shouldn't really happen since user already voted, but just in case
-            return Err(StdError::generic_err(format!(
-                "previous choice {} does not exist in this poll",
-                previous_vote.choice
-            )));
+        for choice in counted_choices(&previous_vote.ballot) {
+            voter_counts[choice as usize] -= 1; // Can't underflow, the previous vote was counted
         }
+        apply_ballot(deps, &mut tally, &previous_vote, false)?;
+        total_power -= previous_vote.voting_power; // Can't underflow, `total_power` includes the previous vote
     } else {
         // If it's a new vote - increment the number of voters
         let mut voters_store = TypedStoreMut::attach(&mut deps.storage);
@@ -439,41 +968,409 @@ fn update_vote<S: Storage, A: Api, Q: Querier>(
         voters_store.store(NUM_OF_VOTERS_KEY, &(num_of_voters + 1))?;
     }
 
-    if let Some(choice_tally) = tally.get_mut(new_vote.choice as usize) {
-        *choice_tally += new_vote.voting_power; // Can't overflow, `choice_tally` <= `gov_token.total_supply()`
-    } else {
-        return Err(StdError::generic_err(format!(
-            "choice {} does not exist in this poll",
-            new_vote.choice
-        )));
+    apply_ballot(deps, &mut tally, &new_vote, true)?;
+    total_power += new_vote.voting_power; // Can't overflow, `total_power` <= `gov_token.total_supply()`
+    for choice in counted_choices(&new_vote.ballot) {
+        // `apply_ballot` already verified that the choices exist
+        voter_counts[choice as usize] += 1;
     }
 
     TypedStoreMut::attach(&mut deps.storage).store(TALLY_KEY, &tally)?;
-    store_vote(deps, voter, new_vote.choice, new_vote.voting_power)?; // This also discards the old vote
+    TypedStoreMut::attach(&mut deps.storage).store(TOTAL_POWER_KEY, &total_power)?;
+    TypedStoreMut::attach(&mut deps.storage).store(VOTER_COUNTS_KEY, &voter_counts)?;
+    store_vote(deps, voter, new_vote.ballot, new_vote.voting_power)?; // This also discards the old vote
 
     Ok(())
 }
 
-fn roll_hash(hash: [u8; 32], voter: &HumanAddr, vote: Vote, salt: String) -> [u8; 32] {
-    let mut extended = Vec::with_capacity(
-        hash.len() + voter.0.len() + size_of::<u8>() + size_of::<u128>() + salt.len(),
+/// Adds a vote's voting power to, or removes it from, every choice that its ballot counts towards
+fn apply_ballot<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    tally: &mut [u128],
+    vote: &Vote,
+    add: bool,
+) -> StdResult<()> {
+    let counted_choices: Vec<(u8, u128)> = match &vote.ballot {
+        Ballot::SingleChoice { choice } => vec![(*choice, vote.voting_power)],
+        Ballot::Approval { choices } => approved_choices(*choices)
+            .into_iter()
+            .map(|choice| (choice, vote.voting_power))
+            .collect(),
+        Ballot::RankedChoice { ranking } => {
+            // Full rankings are kept for the runoff, the tally only holds first preferences
+            update_ranking(deps, ranking, vote.voting_power, add)?;
+            ranking
+                .first()
+                .map(|choice| (*choice, vote.voting_power))
+                .into_iter()
+                .collect()
+        }
+        Ballot::Split { allocations } => split_voting_power(vote.voting_power, allocations)?,
+    };
+
+    for (choice, power) in counted_choices {
+        let choice_tally = tally.get_mut(choice as usize).ok_or_else(|| {
+            StdError::generic_err(format!("choice {} does not exist in this poll", choice))
+        })?;
+
+        if add {
+            *choice_tally += power; // Can't overflow, `choice_tally` <= `gov_token.total_supply()`
+        } else {
+            *choice_tally -= power; // Can't underflow, `choice_tally` >= `old_vote.voting_power`
+        }
+    }
+
+    Ok(())
+}
+
+/// The choices a ballot counts towards, each once. Like the tally, ranked ballots only count
+/// their first preference
+fn counted_choices(ballot: &Ballot) -> Vec<u8> {
+    let mut choices = match ballot {
+        Ballot::SingleChoice { choice } => vec![*choice],
+        Ballot::Approval { choices } => approved_choices(*choices),
+        Ballot::RankedChoice { ranking } => ranking.first().cloned().into_iter().collect(),
+        Ballot::Split { allocations } => allocations.iter().map(|(choice, _)| *choice).collect(),
+    };
+    choices.sort_unstable();
+    choices.dedup();
+
+    choices
+}
+
+/// Splits voting power between the allocated choices. Rounding dust goes to the first allocation,
+/// so the parts always add up to the full voting power and removing a vote is exact
+fn split_voting_power(voting_power: u128, allocations: &[(u8, u16)]) -> StdResult<Vec<(u8, u128)>> {
+    let mut parts = Vec::with_capacity(allocations.len());
+    let mut allocated = 0u128;
+    for (choice, share) in allocations {
+        let (part, _) = mul_div(voting_power, *share as u128, VOTE_SPLIT_SCALE as u128)?;
+        allocated += part; // Can't overflow, the shares add up to `VOTE_SPLIT_SCALE`
+        parts.push((*choice, part));
+    }
+
+    if let Some(first) = parts.first_mut() {
+        first.1 += voting_power - allocated;
+    }
+
+    Ok(parts)
+}
+
+fn approved_choices(choices: u64) -> Vec<u8> {
+    (0..64u8).filter(|i| choices & (1 << i) != 0).collect()
+}
+
+fn validate_ballot(vote_type: &VoteType, ballot: &Ballot, num_of_choices: usize) -> StdResult<()> {
+    let choices = match (vote_type, ballot) {
+        (VoteType::SingleChoice, Ballot::SingleChoice { choice }) => vec![*choice],
+        (VoteType::SingleChoice, Ballot::Split { allocations }) => {
+            let choices: Vec<u8> = allocations.iter().map(|(choice, _)| *choice).collect();
+            if choices
+                .iter()
+                .enumerate()
+                .any(|(i, choice)| choices[..i].contains(choice))
+            {
+                return Err(StdError::generic_err("a choice can only be allocated once"));
+            }
+            // An empty allocation would still count the voter for that choice
+            if allocations.iter().any(|(_, share)| *share == 0) {
+                return Err(StdError::generic_err(
+                    "every allocated choice needs a share of its own",
+                ));
+            }
+            let total_share: u32 = allocations.iter().map(|(_, share)| *share as u32).sum();
+            if total_share != VOTE_SPLIT_SCALE as u32 {
+                return Err(StdError::generic_err(format!(
+                    "allocations must add up to {}",
+                    VOTE_SPLIT_SCALE
+                )));
+            }
+            choices
+        }
+        (VoteType::Approval, Ballot::Approval { choices }) => {
+            if *choices == 0 {
+                return Err(StdError::generic_err(
+                    "you have to approve at least one choice",
+                ));
+            }
+            approved_choices(*choices)
+        }
+        (VoteType::RankedChoice, Ballot::RankedChoice { ranking }) => {
+            if ranking.is_empty() {
+                return Err(StdError::generic_err(
+                    "you have to rank at least one choice",
+                ));
+            }
+            if ranking
+                .iter()
+                .enumerate()
+                .any(|(i, choice)| ranking[..i].contains(choice))
+            {
+                return Err(StdError::generic_err("a choice can only be ranked once"));
+            }
+            ranking.clone()
+        }
+        _ => {
+            return Err(StdError::generic_err(format!(
+                "ballot does not match the {:?} vote type",
+                vote_type
+            )))
+        }
+    };
+
+    if let Some(choice) = choices.iter().find(|c| **c as usize >= num_of_choices) {
+        return Err(StdError::generic_err(format!(
+            "choice {} does not exist in this poll",
+            choice
+        )));
+    }
+
+    Ok(())
+}
+
+/// sha256(previous hash || voter || ballot bytes || voting power as 16 little-endian bytes || salt),
+/// starting from 32 zero bytes. Revealers replay this off-chain to produce the hash `Finalize` takes
+fn roll_hash(hash: [u8; 32], voter: &HumanAddr, vote: Vote, salt: String) -> [u8; 32] {
+    let ballot = ballot_bytes(&vote.ballot);
+    let mut extended = Vec::with_capacity(
+        hash.len() + voter.0.len() + ballot.len() + size_of::<u128>() + salt.len(),
     );
+    extended.extend_from_slice(&hash);
     extended.extend_from_slice(voter.0.as_bytes());
-    extended.extend_from_slice(&vote.choice.to_le_bytes());
+    extended.extend_from_slice(&ballot);
     extended.extend_from_slice(&vote.voting_power.to_le_bytes());
     extended.extend_from_slice(&salt.as_bytes());
 
     Sha256::digest(&extended).into()
 }
 
+fn reason_entropy(prng_seed: &[u8; 32], voter: &HumanAddr) -> [u8; 32] {
+    let mut extended = Vec::with_capacity(prng_seed.len() + voter.0.len());
+    extended.extend_from_slice(prng_seed);
+    extended.extend_from_slice(voter.0.as_bytes());
+
+    Sha256::digest(&extended).into()
+}
+
+fn commitment_hash(voter: &HumanAddr, ballot: &Ballot, salt: &str) -> [u8; 32] {
+    let ballot = ballot_bytes(ballot);
+    let mut extended = Vec::with_capacity(voter.0.len() + ballot.len() + salt.len());
+    extended.extend_from_slice(voter.0.as_bytes());
+    extended.extend_from_slice(&ballot);
+    extended.extend_from_slice(salt.as_bytes());
+
+    Sha256::digest(&extended).into()
+}
+
+fn ballot_bytes(ballot: &Ballot) -> Vec<u8> {
+    match ballot {
+        Ballot::SingleChoice { choice } => vec![*choice],
+        Ballot::Approval { choices } => choices.to_le_bytes().to_vec(),
+        Ballot::RankedChoice { ranking } => {
+            // Length-prefixed, since rankings don't have to include every choice
+            let mut bytes = Vec::with_capacity(ranking.len() + 1);
+            bytes.push(ranking.len() as u8);
+            bytes.extend_from_slice(ranking);
+            bytes
+        }
+        Ballot::Split { allocations } => {
+            // Commits to the whole allocation, not only to the choices
+            let mut bytes = Vec::with_capacity(allocations.len() * 3 + 1);
+            bytes.push(allocations.len() as u8);
+            for (choice, share) in allocations {
+                bytes.push(*choice);
+                bytes.extend_from_slice(&share.to_le_bytes());
+            }
+            bytes
+        }
+    }
+}
+
+/// Returns the result of the poll according to its vote type, along with the voting power behind
+/// the winning choice
+fn tally_result<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &StoredPollConfig,
+    tally: &[u128],
+) -> StdResult<(TallyResult, u128)> {
+    let metadata: PollMetadata = TypedStore::attach(&deps.storage).load(METADATA_KEY)?;
+
+    match metadata.vote_type {
+        VoteType::SingleChoice | VoteType::Approval => {
+            let winner = (0..tally.len())
+                .max_by_key(|c| (tally[*c], Reverse(*c)))
+                .ok_or_else(|| StdError::generic_err("storage is corrupted"))?; // Only `None` when the Vec is empty
+            let result = if metadata.vote_type == VoteType::SingleChoice {
+                TallyResult::SingleChoice {
+                    winner: winner as u8,
+                }
+            } else {
+                TallyResult::Approval {
+                    winner: winner as u8,
+                }
+            };
+
+            Ok((result, tally[winner]))
+        }
+        VoteType::RankedChoice => {
+            let rankings = read_rankings(deps)?;
+            let (winner, rounds) = instant_runoff(config.choices.len(), &rankings);
+            let winning_power = match (winner, rounds.last()) {
+                (Some(winner), Some(last_round)) => last_round[winner as usize],
+                _ => 0,
+            };
+
+            Ok((
+                TallyResult::RankedChoice {
+                    winner,
+                    rounds: rounds
+                        .iter()
+                        .map(|round| round.iter().map(|c| Uint128(*c)).collect())
+                        .collect(),
+                },
+                winning_power,
+            ))
+        }
+    }
+}
+
+/// Runs rounds until a choice holds a majority of the continuing ballots, eliminating the weakest
+/// choice after each round. Ties are broken in favor of the lower choice id
+fn instant_runoff(
+    num_of_choices: usize,
+    rankings: &[(Vec<u8>, u128)],
+) -> (Option<u8>, Vec<Vec<u128>>) {
+    let mut eliminated = vec![false; num_of_choices];
+    let mut rounds = vec![];
+
+    loop {
+        let mut round = vec![0u128; num_of_choices];
+        for (ranking, power) in rankings {
+            // A ballot counts towards its most preferred choice that is still running
+            if let Some(choice) = ranking.iter().find(|c| !eliminated[**c as usize]) {
+                round[*choice as usize] += power;
+            }
+        }
+
+        let continuing: u128 = round.iter().sum();
+        let running: Vec<usize> = (0..num_of_choices).filter(|c| !eliminated[*c]).collect();
+        let leader = running
+            .iter()
+            .cloned()
+            .max_by_key(|c| (round[*c], Reverse(*c)));
+        let weakest = running
+            .iter()
+            .cloned()
+            .min_by_key(|c| (round[*c], Reverse(*c)));
+        let has_majority =
+            leader.map_or(false, |c| round[c] * 2 > continuing || running.len() == 1);
+        rounds.push(round);
+
+        if continuing == 0 {
+            return (None, rounds); // Every ballot was exhausted
+        }
+        if has_majority {
+            return (leader.map(|c| c as u8), rounds);
+        }
+        if let Some(weakest) = weakest {
+            eliminated[weakest] = true;
+        }
+    }
+}
+
+fn poll_outcome(
+    config: &StoredPollConfig,
+    total_power: u128,
+    winner: Option<u8>,
+    winning_power: u128,
+    staked_balance: u128,
+) -> StdResult<PollOutcome> {
+    // Participation is measured against the total staked amount
+    if !reaches_percentage(
+        total_power,
+        staked_balance,
+        config.quorum,
+        config.quorum_inclusive,
+    )? {
+        return Ok(PollOutcome::QuorumNotReached);
+    }
+
+    // Only the affirmative choice can pass the poll, a landslide for any other choice rejects it
+    if winner != Some(config.pass_choice) {
+        return Ok(PollOutcome::Rejected);
+    }
+
+    // The winning choice's share is measured against the voting power that was cast
+    if !reaches_percentage(
+        winning_power,
+        total_power,
+        config.min_threshold,
+        config.threshold_inclusive,
+    )? {
+        return Ok(PollOutcome::Rejected);
+    }
+
+    Ok(PollOutcome::Passed)
+}
+
+/// Checks whether `part / whole` reaches `percentage`%. Compares cross-products so no precision
+/// is lost, and treats an empty `whole` as never reaching any percentage
+fn reaches_percentage(part: u128, whole: u128, percentage: u8, inclusive: bool) -> StdResult<bool> {
+    if whole == 0 {
+        return Ok(false);
+    }
+
+    let overflow = || StdError::generic_err("overflow when calculating percentage");
+    let lhs = part.checked_mul(100).ok_or_else(overflow)?;
+    let rhs = whole.checked_mul(percentage as u128).ok_or_else(overflow)?;
+
+    if inclusive {
+        Ok(lhs >= rhs)
+    } else {
+        Ok(lhs > rhs)
+    }
+}
+
 fn require_vote_ongoing(env: &Env, config: &StoredPollConfig) -> StdResult<()> {
-    if config.end_timestamp < env.block.time {
+    if config.cancelled {
+        return Err(StdError::generic_err("poll was cancelled"));
+    } else if env.block.time < config.start_timestamp {
+        return Err(StdError::generic_err("vote hasn't started yet"));
+    } else if config.end_timestamp < env.block.time {
         return Err(StdError::generic_err("vote has ended"));
     }
 
     Ok(())
 }
 
+/// Returns the number of revealers that have to call `finalize` at `time`
+fn required_reveals(reveal_conf: &StoredRevealConfig, time: u64) -> u64 {
+    match reveal_conf.deadline {
+        Some(deadline) if deadline < time => reveal_conf
+            .committee
+            .fallback_n
+            .unwrap_or(0)
+            .min(reveal_conf.committee.n),
+        _ => reveal_conf.committee.n,
+    }
+}
+
+fn require_reveal_ongoing(env: &Env, config: &StoredPollConfig) -> StdResult<()> {
+    let reveal_end = config
+        .reveal_end_timestamp
+        .ok_or_else(|| StdError::generic_err("this poll doesn't use commit-reveal voting"))?;
+
+    if config.cancelled {
+        return Err(StdError::generic_err("poll was cancelled"));
+    } else if env.block.time <= config.end_timestamp {
+        return Err(StdError::generic_err("reveal period hasn't started yet"));
+    } else if reveal_end < env.block.time {
+        return Err(StdError::generic_err("reveal period has ended"));
+    }
+
+    Ok(())
+}
+
 fn require_vote_finalized_and_valid(config: &StoredPollConfig) -> StdResult<()> {
     if !config.finalized {
         return Err(StdError::generic_err("vote hasn't been finalized yet"));
@@ -490,7 +1387,11 @@ mod tests {
     use cosmwasm_std::testing::{
         mock_dependencies, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
     };
-    use cosmwasm_std::{coins, from_binary, BlockInfo, Coin, ContractInfo, MessageInfo, StdError};
+    use cosmwasm_std::{
+        coins, from_binary, from_slice, BlockInfo, Coin, ContractInfo, Empty, MessageInfo,
+        QuerierResult, QueryRequest, StdError, WasmQuery,
+    };
+    use scrt_finance::lp_staking_msg::{LPStakingQueryAnswer, LPStakingQueryMsg};
     use scrt_finance::secret_vote_types::{PollConfig, RevealCommittee};
 
     pub fn mock_env<U: Into<HumanAddr>>(sender: U, sent: &[Coin], block: u64, time: u64) -> Env {
@@ -519,11 +1420,15 @@ mod tests {
         let mut deps = mock_dependencies(20, &[]);
         let env = mock_env("factory", &[], 0, 0);
 
-        let init_msg = PollInitMsg {
+        (init(&mut deps, env, init_msg()), deps)
+    }
+
+    fn init_msg() -> PollInitMsg {
+        PollInitMsg {
             metadata: PollMetadata {
                 title: "test vote".to_string(),
                 description: "hey hey this is a test vote".to_string(),
-                vote_type: "cool type".to_string(),
+                vote_type: VoteType::SingleChoice,
                 author_addr: Some(HumanAddr("proposer".to_string())),
                 author_alias: "proposer".into(),
             },
@@ -531,20 +1436,76 @@ mod tests {
                 duration: 1000,
                 quorum: 33,
                 min_threshold: 0,
+                quorum_inclusive: true,
+                threshold_inclusive: false,
+                reveal_period: None,
+                voting_delay: None,
+                snapshot_voting: false,
+                pass_choice: 0,
             },
             reveal_com: RevealCommittee {
                 n: 2,
                 revealers: vec![HumanAddr("rev1".into()), HumanAddr("rev2".into())],
+                timeout: None,
+                fallback_n: None,
             },
             choices: vec!["Yes".into(), "No".into()],
             staking_pool: SecretContract {
                 address: HumanAddr("staking pool".to_string()),
                 contract_hash: "".to_string(),
             },
+            extra_pools: vec![],
             init_hook: None,
-        };
+            prng_seed: Binary::from(b"seed".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_init_timestamps() {
+        let mut msg = init_msg();
+        msg.config.reveal_period = Some(500);
+        let mut deps = mock_dependencies(20, &[]);
+        init(&mut deps, mock_env("factory", &[], 0, 100), msg).unwrap();
+        let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        assert_eq!(config.end_timestamp, 1100);
+        assert_eq!(config.reveal_end_timestamp, Some(1600));
+
+        let mut msg = init_msg();
+        msg.reveal_com.timeout = Some(u64::MAX);
+        let mut deps = mock_dependencies(20, &[]);
+        let err = init(&mut deps, mock_env("factory", &[], 0, 100), msg).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("reveal committee timeout is too long")
+        );
+
+        let mut msg = init_msg();
+        msg.config.reveal_period = Some(u64::MAX);
+        let mut deps = mock_dependencies(20, &[]);
+        let err = init(&mut deps, mock_env("factory", &[], 0, 100), msg).unwrap_err();
+        assert_eq!(err, StdError::generic_err("reveal period is too long"));
 
-        (init(&mut deps, env, init_msg), deps)
+        let mut msg = init_msg();
+        msg.config.voting_delay = Some(u64::MAX - 100);
+        let mut deps = mock_dependencies(20, &[]);
+        let err = init(&mut deps, mock_env("factory", &[], 0, 100), msg).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("voting delay and duration are too long")
+        );
+
+        // Snapshot polls use the stake as of the block before they were created
+        let mut msg = init_msg();
+        msg.config.snapshot_voting = true;
+        let mut deps = mock_dependencies(20, &[]);
+        init(&mut deps, mock_env("factory", &[], 500, 100), msg).unwrap();
+        let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        assert_eq!(config.snapshot_block, Some(499));
+
+        let mut deps = mock_dependencies(20, &[]);
+        init(&mut deps, mock_env("factory", &[], 500, 100), init_msg()).unwrap();
+        let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        assert_eq!(config.snapshot_block, None);
     }
 
     #[test]
@@ -555,7 +1516,7 @@ mod tests {
             metadata: PollMetadata {
                 title: "test_vote_info".to_string(),
                 description: "test_vote_info".to_string(),
-                vote_type: "cool type".to_string(),
+                vote_type: VoteType::SingleChoice,
                 author_addr: Some(HumanAddr("proposer".to_string())),
                 author_alias: "proposer".into(),
             },
@@ -563,17 +1524,27 @@ mod tests {
                 duration: 1000,
                 quorum: 33,
                 min_threshold: 0,
+                quorum_inclusive: true,
+                threshold_inclusive: false,
+                reveal_period: None,
+                voting_delay: Some(100),
+                snapshot_voting: false,
+                pass_choice: 0,
             },
             reveal_com: RevealCommittee {
                 n: 2,
                 revealers: vec![HumanAddr("rev1".into()), HumanAddr("rev2".into())],
+                timeout: None,
+                fallback_n: None,
             },
             choices: vec!["Yes".into(), "No".into()],
             staking_pool: SecretContract {
                 address: HumanAddr("staking pool".to_string()),
                 contract_hash: "".to_string(),
             },
+            extra_pools: vec![],
             init_hook: None,
+            prng_seed: Binary::from(b"seed".to_vec()),
         };
         init(&mut deps, env, init_msg).unwrap();
 
@@ -584,26 +1555,49 @@ mod tests {
                 metadata: PollMetadata {
                     title: "test_vote_info".to_string(),
                     description: "test_vote_info".to_string(),
-                    vote_type: "cool type".to_string(),
+                    vote_type: VoteType::SingleChoice,
                     author_addr: Some(HumanAddr("proposer".to_string())),
                     author_alias: "proposer".into(),
                 },
                 config: StoredPollConfig {
-                    end_timestamp: 1000,
+                    start_timestamp: 100,
+                    end_timestamp: 1100,
+                    reveal_end_timestamp: None,
                     quorum: 33,
                     min_threshold: 0,
+                    quorum_inclusive: true,
+                    threshold_inclusive: false,
                     choices: vec!["Yes".into(), "No".into()],
                     finalized: false,
                     valid: false,
-                    rolling_hash: [0u8; 32]
+                    outcome: None,
+                    rolling_hash: [0u8; 32],
+                    cancelled: false,
+                    snapshot_block: None,
+                    pass_choice: 0,
                 },
                 reveal_com: RevealCommittee {
                     n: 2,
                     revealers: vec![HumanAddr("rev1".into()), HumanAddr("rev2".into())],
+                    timeout: None,
+                    fallback_n: None,
                 }
             })
             .unwrap()
+        );
+
+        // Voting only opens after the discussion period
+        let err = vote(
+            &mut deps,
+            mock_env("user", &[], 0, 50),
+            Ballot::SingleChoice { choice: 0 },
+            "key".into(),
+            "salt".into(),
+            None,
+            false,
         )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("vote hasn't started yet"));
     }
 
     #[test]
@@ -616,7 +1610,7 @@ mod tests {
             &HumanAddr("user".into()),
             None,
             Vote {
-                choice: 0,
+                ballot: Ballot::SingleChoice { choice: 0 },
                 voting_power: 100,
             },
         )
@@ -646,6 +1640,659 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_results() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        for (voter, ballot, voting_power) in vec![
+            ("alice", Ballot::SingleChoice { choice: 0 }, 100),
+            (
+                "bob",
+                Ballot::Split {
+                    allocations: vec![(0, 5_000), (1, 5_000)],
+                },
+                200,
+            ),
+            ("carol", Ballot::SingleChoice { choice: 0 }, 50),
+            ("carol", Ballot::SingleChoice { choice: 1 }, 50), // Changed their mind
+        ] {
+            let voter = HumanAddr(voter.into());
+            let prev_vote = read_vote(&deps, &voter).ok();
+            update_vote(
+                &mut deps,
+                &voter,
+                prev_vote,
+                Vote {
+                    ballot,
+                    voting_power,
+                },
+            )
+            .unwrap();
+        }
+
+        let err = query_results(&deps).unwrap_err();
+        assert_eq!(err, StdError::generic_err("vote hasn't been finalized yet"));
+
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.finalized = true;
+        config.outcome = Some(PollOutcome::QuorumNotReached);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config)
+            .unwrap();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(FINAL_STAKED_KEY, &1_400_u128)
+            .unwrap();
+
+        // Unlike the tally, results are available for polls that didn't pass
+        let res = query_results(&deps).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Results {
+                choices: vec!["Yes".into(), "No".into()],
+                tally: vec![Uint128(200), Uint128(150)],
+                voters_per_choice: vec![2, 2],
+                num_of_voters: 3,
+                total_power: Uint128(350),
+                total_staked: Uint128(1_400),
+                turnout: 2_500,
+                outcome: Some(PollOutcome::QuorumNotReached),
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_poll_outcome() {
+        let (init_result, deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.min_threshold = 60;
+
+        // Quorum is 33%, inclusive
+        assert_eq!(
+            poll_outcome(&config, 33, Some(0), 33, 100).unwrap(),
+            PollOutcome::Passed
+        );
+        assert_eq!(
+            poll_outcome(&config, 32, Some(0), 32, 100).unwrap(),
+            PollOutcome::QuorumNotReached
+        );
+        config.quorum_inclusive = false;
+        assert_eq!(
+            poll_outcome(&config, 33, Some(0), 33, 100).unwrap(),
+            PollOutcome::QuorumNotReached
+        );
+        config.quorum_inclusive = true;
+
+        // A landslide for anything but the pass choice rejects the poll
+        assert_eq!(
+            poll_outcome(&config, 100, Some(1), 90, 100).unwrap(),
+            PollOutcome::Rejected
+        );
+        assert_eq!(
+            poll_outcome(&config, 100, None, 0, 100).unwrap(),
+            PollOutcome::Rejected
+        );
+        config.pass_choice = 1;
+        assert_eq!(
+            poll_outcome(&config, 100, Some(1), 90, 100).unwrap(),
+            PollOutcome::Passed
+        );
+        config.pass_choice = 0;
+        config.quorum_inclusive = false;
+
+        // Threshold is 60% of the votes cast, exclusive
+        assert_eq!(
+            poll_outcome(&config, 100, Some(0), 60, 100).unwrap(),
+            PollOutcome::Rejected
+        );
+        assert_eq!(
+            poll_outcome(&config, 100, Some(0), 61, 100).unwrap(),
+            PollOutcome::Passed
+        );
+        config.threshold_inclusive = true;
+        assert_eq!(
+            poll_outcome(&config, 100, Some(0), 60, 100).unwrap(),
+            PollOutcome::Passed
+        );
+
+        // Nothing is staked, or nobody voted
+        assert_eq!(
+            poll_outcome(&config, 0, Some(0), 0, 0).unwrap(),
+            PollOutcome::QuorumNotReached
+        );
+        config.quorum = 0;
+        config.quorum_inclusive = true;
+        assert_eq!(
+            poll_outcome(&config, 0, Some(0), 0, 100).unwrap(),
+            PollOutcome::Rejected
+        );
+    }
+
+    #[test]
+    fn test_approval_vote() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut metadata: PollMetadata = TypedStore::attach(&deps.storage)
+            .load(METADATA_KEY)
+            .unwrap();
+        metadata.vote_type = VoteType::Approval;
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(METADATA_KEY, &metadata)
+            .unwrap();
+
+        let err = validate_ballot(&metadata.vote_type, &Ballot::Approval { choices: 0b100 }, 2)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("choice 2 does not exist in this poll")
+        );
+        let err = validate_ballot(&metadata.vote_type, &Ballot::SingleChoice { choice: 0 }, 2)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("ballot does not match the Approval vote type")
+        );
+
+        let user = HumanAddr("user".into());
+        update_vote(
+            &mut deps,
+            &user,
+            None,
+            Vote {
+                ballot: Ballot::Approval { choices: 0b11 },
+                voting_power: 100,
+            },
+        )
+        .unwrap();
+        update_vote(
+            &mut deps,
+            &HumanAddr("user2".into()),
+            None,
+            Vote {
+                ballot: Ballot::Approval { choices: 0b10 },
+                voting_power: 50,
+            },
+        )
+        .unwrap();
+
+        // Voting power update moves every approval
+        let prev_vote = read_vote(&deps, &user).unwrap();
+        update_vote(
+            &mut deps,
+            &user,
+            Some(prev_vote.clone()),
+            Vote {
+                ballot: prev_vote.ballot,
+                voting_power: 30,
+            },
+        )
+        .unwrap();
+
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        assert_eq!(tally, vec![30, 80]);
+        let total_power: u128 = TypedStore::attach(&deps.storage)
+            .load(TOTAL_POWER_KEY)
+            .unwrap();
+        assert_eq!(total_power, 80);
+
+        let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        let (result, winning_power) = tally_result(&deps, &config, &tally).unwrap();
+        assert_eq!(result, TallyResult::Approval { winner: 1 });
+        assert_eq!(winning_power, 80);
+    }
+
+    #[test]
+    fn test_split_vote() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let err = validate_ballot(
+            &VoteType::SingleChoice,
+            &Ballot::Split {
+                allocations: vec![(0, 6000), (1, 3000)],
+            },
+            2,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("allocations must add up to 10000")
+        );
+        let err = validate_ballot(
+            &VoteType::SingleChoice,
+            &Ballot::Split {
+                allocations: vec![(0, 6000), (0, 4000)],
+            },
+            2,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("a choice can only be allocated once")
+        );
+        let err = validate_ballot(
+            &VoteType::SingleChoice,
+            &Ballot::Split {
+                allocations: vec![(0, 10000), (1, 0)],
+            },
+            2,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("every allocated choice needs a share of its own")
+        );
+
+        let user = HumanAddr("user".into());
+        let ballot = Ballot::Split {
+            allocations: vec![(0, 6000), (1, 4000)],
+        };
+        validate_ballot(&VoteType::SingleChoice, &ballot, 2).unwrap();
+        update_vote(
+            &mut deps,
+            &user,
+            None,
+            Vote {
+                ballot: ballot.clone(),
+                voting_power: 101,
+            },
+        )
+        .unwrap();
+
+        // Rounding dust goes to the first allocation
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        assert_eq!(tally, vec![61, 40]);
+
+        let prev_vote = read_vote(&deps, &user).unwrap();
+        update_vote(
+            &mut deps,
+            &user,
+            Some(prev_vote),
+            Vote {
+                ballot,
+                voting_power: 50,
+            },
+        )
+        .unwrap();
+
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        assert_eq!(tally, vec![30, 20]);
+        let total_power: u128 = TypedStore::attach(&deps.storage)
+            .load(TOTAL_POWER_KEY)
+            .unwrap();
+        assert_eq!(total_power, 50);
+    }
+
+    #[test]
+    fn test_commit_reveal() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.reveal_end_timestamp = Some(1500);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config)
+            .unwrap();
+
+        let user = HumanAddr("user".into());
+        let ballot = Ballot::SingleChoice { choice: 1 };
+        store_commitment(
+            &mut deps,
+            &user,
+            &Commitment {
+                hash: commitment_hash(&user, &ballot, "salt").to_vec(),
+                voting_power: 100,
+            },
+        )
+        .unwrap();
+
+        let res = query_has_voted(&deps, user.clone()).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::HasVoted { has_voted: true }).unwrap()
+        );
+
+        let err = reveal_vote(
+            &mut deps,
+            mock_env("user", &[], 0, 900),
+            ballot.clone(),
+            "salt".into(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("reveal period hasn't started yet")
+        );
+
+        let err = reveal_vote(
+            &mut deps,
+            mock_env("user", &[], 0, 1100),
+            ballot.clone(),
+            "pepper".into(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("ballot and salt don't match the commitment")
+        );
+
+        // Nothing is tallied until the vote is revealed
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        assert_eq!(tally, vec![0, 0]);
+
+        reveal_vote(
+            &mut deps,
+            mock_env("user", &[], 0, 1100),
+            ballot.clone(),
+            "salt".into(),
+        )
+        .unwrap();
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        assert_eq!(tally, vec![0, 100]);
+
+        let err = reveal_vote(
+            &mut deps,
+            mock_env("user", &[], 0, 1200),
+            ballot,
+            "salt".into(),
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("no committed vote to reveal"));
+
+        let err = finalize(&mut deps, mock_env("rev1", &[], 0, 1500), "".into()).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("reveal period has not ended yet")
+        );
+    }
+
+    #[test]
+    fn test_finalize_fallback() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut reveal_conf: StoredRevealConfig = TypedStore::attach(&deps.storage)
+            .load(REVEAL_CONFIG)
+            .unwrap();
+        reveal_conf.deadline = Some(2000);
+        reveal_conf.committee.fallback_n = Some(1);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(REVEAL_CONFIG, &reveal_conf)
+            .unwrap();
+
+        let rolling_hash = hex::encode([0u8; 32]);
+        let err = finalize(
+            &mut deps,
+            mock_env("stranger", &[], 0, 1500),
+            rolling_hash.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+
+        let res = finalize(&mut deps, mock_env("rev1", &[], 0, 1500), rolling_hash).unwrap();
+        let answer: FinalizeAnswer = from_binary(&res.data.unwrap()).unwrap();
+        assert!(!answer.finalized);
+
+        let reveal_conf: StoredRevealConfig = TypedStore::attach(&deps.storage)
+            .load(REVEAL_CONFIG)
+            .unwrap();
+        assert_eq!(required_reveals(&reveal_conf, 1500), 2);
+        assert_eq!(required_reveals(&reveal_conf, 2000), 2);
+        assert_eq!(required_reveals(&reveal_conf, 2001), 1);
+
+        // Without a fallback quorum, anyone can finalize after the deadline
+        let mut reveal_conf = reveal_conf;
+        reveal_conf.committee.fallback_n = None;
+        assert_eq!(required_reveals(&reveal_conf, 2001), 0);
+    }
+
+    #[test]
+    fn test_vote_reasons() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        for (voter, choice, reason, disclose_voter) in vec![
+            ("alice", 0, "first reason", true),
+            ("bob", 1, "second reason", false),
+            ("alice", 1, "changed my mind", true),
+        ] {
+            let voter = HumanAddr(voter.into());
+            let prev_vote = read_vote(&deps, &voter).ok();
+            update_vote(
+                &mut deps,
+                &voter,
+                prev_vote,
+                Vote {
+                    ballot: Ballot::SingleChoice { choice },
+                    voting_power: 10,
+                },
+            )
+            .unwrap();
+            store_reason(
+                &mut deps,
+                StoredReason {
+                    voter,
+                    reason: reason.into(),
+                    disclose_voter,
+                },
+                // Always shuffles new reasons to the front
+                &[0u8; 32],
+            )
+            .unwrap();
+        }
+
+        let err = query_reasons(&deps, 0, 10).unwrap_err();
+        assert_eq!(err, StdError::generic_err("vote hasn't been finalized yet"));
+
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.finalized = true;
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config)
+            .unwrap();
+
+        // Bob's reason displaced alice's, and her second vote replaced it in its new position
+        let res = query_reasons(&deps, 0, 10).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::Reasons {
+                reasons: vec![
+                    VoteReason {
+                        voter: None,
+                        ballot: Ballot::SingleChoice { choice: 1 },
+                        reason: "second reason".into(),
+                    },
+                    VoteReason {
+                        voter: Some(HumanAddr("alice".into())),
+                        ballot: Ballot::SingleChoice { choice: 1 },
+                        reason: "changed my mind".into(),
+                    },
+                ],
+                total: 2,
+            })
+            .unwrap()
+        );
+
+        let res = query_reasons(&deps, 1, 1).unwrap();
+        let answer: QueryAnswer = from_binary(&res).unwrap();
+        match answer {
+            QueryAnswer::Reasons { reasons, total } => {
+                assert_eq!(reasons.len(), 1);
+                assert_eq!(reasons[0].reason, "changed my mind");
+                assert_eq!(total, 2);
+            }
+            _ => panic!("unexpected answer"),
+        }
+
+        let err = query_reasons(&deps, u32::MAX, 10).unwrap_err();
+        assert_eq!(err, StdError::generic_err("page is out of range"));
+        let err = query_rolling_hash_history(&deps, u32::MAX, 10).unwrap_err();
+        assert_eq!(err, StdError::generic_err("page is out of range"));
+
+        // Oversized pages are capped instead of overflowing
+        let res = query_reasons(&deps, 1, u32::MAX).unwrap();
+        let answer: QueryAnswer = from_binary(&res).unwrap();
+        match answer {
+            QueryAnswer::Reasons { reasons, total } => {
+                assert!(reasons.is_empty());
+                assert_eq!(total, 2);
+            }
+            _ => panic!("unexpected answer"),
+        }
+    }
+
+    #[test]
+    fn test_rolling_hash_history() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut hash = [0u8; 32];
+        let mut chain = vec![];
+        for (voter, choice) in vec![("alice", 0), ("bob", 1), ("alice", 1)] {
+            let voter = HumanAddr(voter.into());
+            let vote = Vote {
+                ballot: Ballot::SingleChoice { choice },
+                voting_power: 10,
+            };
+            hash = roll_hash(hash, &voter, vote, "salt".into());
+            chain.push(hex::encode(&hash));
+            push_rolling_hash(&mut deps, &voter, hash).unwrap();
+        }
+
+        // Each hash commits to the one before it. Off-chain revealers must produce the same chain
+        assert_eq!(
+            chain,
+            vec![
+                "1305abb647514ec2ef89a442dda86eb0c07b111462f71243beb3b6ef497bb90d",
+                "b77fa5cf9e1bc220266129458c939237dd479d607253ca8775a055d8b3422f1f",
+                "668043da090b094c9bc5ed372bf71785b1fff8512028d62eff2f2dbdb69e6b11",
+            ]
+        );
+
+        // A receipt points at the voter's latest vote
+        assert_eq!(
+            read_receipt(&deps, &HumanAddr("alice".into())).unwrap(),
+            Some((2, hash))
+        );
+        assert_eq!(
+            read_receipt(&deps, &HumanAddr("carol".into())).unwrap(),
+            None
+        );
+
+        let err = query_rolling_hash_history(&deps, 0, 10).unwrap_err();
+        assert_eq!(err, StdError::generic_err("vote hasn't been finalized yet"));
+
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.finalized = true;
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config)
+            .unwrap();
+
+        let res = query_rolling_hash_history(&deps, 0, 10).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::RollingHashHistory {
+                hashes: chain.clone(),
+                total: 3,
+            })
+            .unwrap()
+        );
+
+        let res = query_rolling_hash_history(&deps, 1, 2).unwrap();
+        assert_eq!(
+            res,
+            to_binary(&QueryAnswer::RollingHashHistory {
+                hashes: vec![chain[2].clone()],
+                total: 3,
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_instant_runoff() {
+        let rankings = vec![(vec![0], 40), (vec![1, 0], 35), (vec![2, 1], 25)];
+        let (winner, rounds) = instant_runoff(3, &rankings);
+        assert_eq!(winner, Some(1));
+        assert_eq!(rounds, vec![vec![40, 35, 25], vec![40, 60, 0]]);
+
+        // Exhausted ballots don't count towards the majority
+        let rankings = vec![(vec![0], 40), (vec![1], 35), (vec![2], 25)];
+        let (winner, rounds) = instant_runoff(3, &rankings);
+        assert_eq!(winner, Some(0));
+        assert_eq!(rounds.last().unwrap(), &vec![40, 35, 0]);
+
+        let (winner, _) = instant_runoff(3, &[]);
+        assert_eq!(winner, None);
+    }
+
+    #[test]
+    fn test_ranked_choice_vote() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let mut metadata: PollMetadata = TypedStore::attach(&deps.storage)
+            .load(METADATA_KEY)
+            .unwrap();
+        metadata.vote_type = VoteType::RankedChoice;
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(METADATA_KEY, &metadata)
+            .unwrap();
+
+        let ranked = |ranking: Vec<u8>, voting_power: u128| Vote {
+            ballot: Ballot::RankedChoice { ranking },
+            voting_power,
+        };
+        for (voter, ranking, power) in vec![
+            ("alice", vec![0], 40),
+            ("bob", vec![1, 0], 20),
+            ("carol", vec![1, 0], 15),
+        ] {
+            update_vote(
+                &mut deps,
+                &HumanAddr(voter.into()),
+                None,
+                ranked(ranking, power),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            read_rankings(&deps).unwrap(),
+            vec![(vec![0], 40), (vec![1, 0], 35)]
+        );
+
+        // A changed vote only moves power between the two rankings
+        let bob = HumanAddr("bob".into());
+        let prev_vote = read_vote(&deps, &bob).unwrap();
+        update_vote(&mut deps, &bob, Some(prev_vote), ranked(vec![0], 20)).unwrap();
+        let prev_vote = read_vote(&deps, &HumanAddr("carol".into())).unwrap();
+        update_vote(
+            &mut deps,
+            &HumanAddr("carol".into()),
+            Some(prev_vote),
+            ranked(vec![0, 1], 15),
+        )
+        .unwrap();
+        assert_eq!(
+            read_rankings(&deps).unwrap(),
+            vec![(vec![0], 60), (vec![0, 1], 15)]
+        );
+
+        let config: StoredPollConfig = TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        let tally: Vec<u128> = TypedStore::attach(&deps.storage).load(TALLY_KEY).unwrap();
+        let (result, winning_power) = tally_result(&deps, &config, &tally).unwrap();
+        assert_eq!(result.winner(), Some(0));
+        assert_eq!(winning_power, 75);
+    }
+
     #[test]
     fn test_tally_before_ended() {}
 
@@ -665,11 +2312,284 @@ mod tests {
     fn test_query_vote() {}
 
     #[test]
-    fn test_update_voting_power() {}
+    fn test_update_voting_power() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+        let lp_pool = SecretContract {
+            address: HumanAddr("lp pool".to_string()),
+            contract_hash: "".to_string(),
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(
+                EXTRA_POOLS_KEY,
+                &vec![WeightedPool {
+                    pool: lp_pool.clone(),
+                    multiplier: 15_000,
+                }],
+            )
+            .unwrap();
+
+        let user = HumanAddr("user".into());
+        update_vote(
+            &mut deps,
+            &user,
+            None,
+            Vote {
+                ballot: Ballot::SingleChoice { choice: 0 },
+                voting_power: 100,
+            },
+        )
+        .unwrap();
+        store_power_sources(&mut deps, &user, &[100, 0]).unwrap();
+
+        let err = update_voting_power(
+            &mut deps,
+            mock_env("user", &[], 0, 500),
+            user.clone(),
+            1000,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+
+        // Each pool only updates its own share of the voting power
+        update_voting_power(
+            &mut deps,
+            mock_env("factory", &[], 0, 500),
+            user.clone(),
+            40,
+            Some(lp_pool.address.clone()),
+        )
+        .unwrap();
+        assert_eq!(read_vote(&deps, &user).unwrap().voting_power, 160);
+
+        update_voting_power(
+            &mut deps,
+            mock_env("factory", &[], 0, 500),
+            user.clone(),
+            50,
+            None,
+        )
+        .unwrap();
+        assert_eq!(read_vote(&deps, &user).unwrap().voting_power, 110);
+        assert_eq!(
+            read_power_sources(&deps, &user).unwrap(),
+            Some(vec![50, 40])
+        );
+
+        // Pools the poll doesn't know about are ignored
+        update_voting_power(
+            &mut deps,
+            mock_env("factory", &[], 0, 500),
+            user.clone(),
+            1000,
+            Some(HumanAddr("new pool".into())),
+        )
+        .unwrap();
+        assert_eq!(read_vote(&deps, &user).unwrap().voting_power, 110);
+
+        // Snapshot polls keep the voting power from the poll's creation block
+        let mut config: StoredPollConfig =
+            TypedStore::attach(&deps.storage).load(CONFIG_KEY).unwrap();
+        config.snapshot_block = Some(12_345);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(CONFIG_KEY, &config)
+            .unwrap();
+        let res = update_voting_power(
+            &mut deps,
+            mock_env("factory", &[], 0, 500),
+            user.clone(),
+            1000,
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, HandleResponse::default());
+        assert_eq!(read_vote(&deps, &user).unwrap().voting_power, 110);
+    }
 
     #[test]
     fn test_vote_after_ended() {}
 
     #[test]
     fn test_finalize_before_ended() {}
+
+    #[test]
+    fn test_cancel() {
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok());
+
+        let err = cancel(&mut deps, mock_env("stranger", &[], 0, 100)).unwrap_err();
+        assert_eq!(err, StdError::unauthorized());
+        let res = cancel(&mut deps, mock_env("proposer", &[], 0, 100)).unwrap();
+        assert_eq!(
+            res.log,
+            vec![
+                log(log_keys::ACTION, log_actions::CANCEL),
+                log(log_keys::POLL_ADDR, MOCK_CONTRACT_ADDR),
+                log(log_keys::STATUS, "cancelled"),
+            ]
+        );
+        let err = cancel(&mut deps, mock_env("factory", &[], 0, 100)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll was already cancelled"));
+
+        let err = vote(
+            &mut deps,
+            mock_env("user", &[], 0, 100),
+            Ballot::SingleChoice { choice: 0 },
+            "key".into(),
+            "salt".into(),
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll was cancelled"));
+        let err = finalize(
+            &mut deps,
+            mock_env("rev1", &[], 0, 1100),
+            hex::encode([0u8; 32]),
+        )
+        .unwrap_err();
+        assert_eq!(err, StdError::generic_err("poll was cancelled"));
+
+        // Once someone voted, only the factory can cancel
+        let (_, mut deps) = init_helper();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(NUM_OF_VOTERS_KEY, &1_u64)
+            .unwrap();
+        let err = cancel(&mut deps, mock_env("proposer", &[], 0, 100)).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll can't be cancelled by its author after votes were cast")
+        );
+        cancel(&mut deps, mock_env("factory", &[], 0, 1100)).unwrap();
+
+        // Nor once voting ended, even if nobody voted
+        let (_, mut deps) = init_helper();
+        cancel(&mut deps, mock_env("proposer", &[], 0, 1000)).unwrap();
+        let (_, mut deps) = init_helper();
+        let err = cancel(&mut deps, mock_env("proposer", &[], 0, 1001)).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("poll can't be cancelled by its author after voting ended")
+        );
+        cancel(&mut deps, mock_env("factory", &[], 0, 1001)).unwrap();
+    }
+
+    // Answers like the staking pools would, except for the pools named after how they fail
+    struct PoolsQuerier;
+
+    impl Querier for PoolsQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            let request: QueryRequest<Empty> = from_slice(bin_request).unwrap();
+            let (pool, msg) = match request {
+                QueryRequest::Wasm(WasmQuery::Smart {
+                    contract_addr, msg, ..
+                }) => (
+                    contract_addr,
+                    from_binary::<LPStakingQueryMsg>(&msg).unwrap(),
+                ),
+                _ => panic!("unexpected query"),
+            };
+
+            let answer = match (pool.0.as_str(), msg) {
+                ("broken_pool", _) => return Ok(Err(StdError::generic_err("pool is broken"))),
+                ("no_key_pool", _) => LPStakingQueryAnswer::QueryError {
+                    msg: "Wrong viewing key for this address or viewing key not set".into(),
+                },
+                (_, LPStakingQueryMsg::VotingPower { .. }) => LPStakingQueryAnswer::VotingPower {
+                    amount: Uint128(100),
+                },
+                (_, LPStakingQueryMsg::TotalLocked {}) => LPStakingQueryAnswer::TotalLocked {
+                    amount: Uint128(1000),
+                },
+                (_, LPStakingQueryMsg::VotingPowerAt { block, .. }) => {
+                    LPStakingQueryAnswer::VotingPowerAt {
+                        amount: Uint128(block as u128),
+                    }
+                }
+                (_, LPStakingQueryMsg::TotalLockedAt { block }) => {
+                    LPStakingQueryAnswer::TotalLockedAt {
+                        amount: Uint128(block as u128 * 10),
+                    }
+                }
+                _ => panic!("unexpected query"),
+            };
+            Ok(to_binary(&answer))
+        }
+    }
+
+    fn pools_deps(extra_pools: &[&str]) -> Extern<MockStorage, MockApi, PoolsQuerier> {
+        let pool = |address: &str| SecretContract {
+            address: HumanAddr(address.into()),
+            contract_hash: "".into(),
+        };
+        let mut deps = Extern {
+            storage: MockStorage::default(),
+            api: MockApi::new(20),
+            querier: PoolsQuerier,
+        };
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(STAKING_POOL_KEY, &pool("staking_pool"))
+            .unwrap();
+        let extra_pools: Vec<WeightedPool> = extra_pools
+            .iter()
+            .map(|address| WeightedPool {
+                pool: pool(address),
+                multiplier: 20_000,
+            })
+            .collect();
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(EXTRA_POOLS_KEY, &extra_pools)
+            .unwrap();
+
+        deps
+    }
+
+    #[test]
+    fn test_extra_pool_errors() {
+        let voter = HumanAddr("voter".into());
+
+        // A pool that doesn't accept the voter's key counts as zero
+        let deps = pools_deps(&["lp_pool", "no_key_pool"]);
+        let powers = query_voting_power(&deps, voter.clone(), "key".into(), None).unwrap();
+        assert_eq!(powers, vec![100, 100, 0]);
+
+        // Any other error fails the vote instead of silently dropping the voter's power
+        let deps = pools_deps(&["lp_pool", "broken_pool"]);
+        let err = query_voting_power(&deps, voter.clone(), "key".into(), None).unwrap_err();
+        assert_eq!(err, StdError::generic_err("pool is broken"));
+
+        // The SEFI staking pool always has to accept the key
+        let mut deps = pools_deps(&[]);
+        TypedStoreMut::attach(&mut deps.storage)
+            .store(
+                STAKING_POOL_KEY,
+                &SecretContract {
+                    address: HumanAddr("no_key_pool".into()),
+                    contract_hash: "".into(),
+                },
+            )
+            .unwrap();
+        let err = query_voting_power(&deps, voter, "key".into(), None).unwrap_err();
+        assert_eq!(
+            err,
+            StdError::generic_err("Wrong viewing key for this address or viewing key not set")
+        );
+    }
+
+    #[test]
+    fn test_snapshot_queries() {
+        let deps = pools_deps(&["lp_pool"]);
+        let voter = HumanAddr("voter".into());
+
+        // Live polls ask for the current voting power, which includes delegations
+        let powers = query_voting_power(&deps, voter.clone(), "key".into(), None).unwrap();
+        assert_eq!(powers, vec![100, 100]);
+        assert_eq!(query_staking_balance(&deps, None).unwrap(), 1000 + 2_000);
+
+        // Snapshot polls ask for the voting power checkpointed at their block, delegations included
+        let powers = query_voting_power(&deps, voter, "key".into(), Some(99)).unwrap();
+        assert_eq!(powers, vec![99, 99]);
+        assert_eq!(query_staking_balance(&deps, Some(99)).unwrap(), 990 + 1_980);
+    }
 }
@@ -1,7 +1,7 @@
 use crate::state::StoredPollConfig;
 use cosmwasm_std::{HumanAddr, Uint128};
 use schemars::JsonSchema;
-use scrt_finance::secret_vote_types::{PollMetadata, RevealCommittee};
+use scrt_finance::secret_vote_types::{Ballot, PollMetadata, PollOutcome, RevealCommittee};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -9,8 +9,43 @@ use serde::{Deserialize, Serialize};
 pub struct FinalizeAnswer {
     pub finalized: bool,
     pub valid: Option<bool>,
+    pub outcome: Option<PollOutcome>,
     pub choices: Option<Vec<String>>,
     pub tally: Option<Vec<Uint128>>,
+    pub result: Option<TallyResult>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TallyResult {
+    SingleChoice {
+        winner: u8,
+    },
+    Approval {
+        winner: u8,
+    },
+    RankedChoice {
+        winner: Option<u8>,        // `None` if every ballot was exhausted
+        rounds: Vec<Vec<Uint128>>, // First preferences of continuing ballots in each round
+    },
+}
+
+impl TallyResult {
+    pub fn winner(&self) -> Option<u8> {
+        match self {
+            TallyResult::SingleChoice { winner } | TallyResult::Approval { winner } => {
+                Some(*winner)
+            }
+            TallyResult::RankedChoice { winner, .. } => *winner,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct VoteReason {
+    pub voter: Option<HumanAddr>, // Only set if the voter chose to disclose it
+    pub ballot: Ballot,
+    pub reason: String,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -21,13 +56,18 @@ pub enum QueryMsg {
     VoteInfo {},
     HasVoted { voter: HumanAddr },
     Tally {},
+    Results {},
     NumberOfVoters {},
     RevealCommittee {},
     Revealed {},
     RollingHash {},
+    // Always empty for commit-reveal polls, which don't take reasons
+    Reasons { page: u32, page_size: u32 },
+    RollingHashHistory { page: u32, page_size: u32 },
 
     // Authenticated
     Vote { voter: HumanAddr, key: String },
+    VoteReceipt { voter: HumanAddr, key: String },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -48,8 +88,18 @@ pub enum QueryAnswer {
         choices: Vec<String>,
         tally: Vec<Uint128>,
     },
+    Results {
+        choices: Vec<String>,
+        tally: Vec<Uint128>,
+        voters_per_choice: Vec<u64>, // Distinct voters whose ballot counts towards each choice
+        num_of_voters: u64,
+        total_power: Uint128,
+        total_staked: Uint128, // At finalization, the quorum is measured against it
+        turnout: u32,          // In basis points (X/10000) of `total_staked`
+        outcome: Option<PollOutcome>,
+    },
     Vote {
-        choice: u8,
+        ballot: Ballot,
         voting_power: Uint128,
     },
     NumberOfVoters {
@@ -62,10 +112,26 @@ pub enum QueryAnswer {
         required: u64,
         num_revealed: u64,
         revealed: Vec<HumanAddr>,
+        deadline: Option<u64>,
+        fallback_required: Option<u64>, // `None` means anyone can finalize after the deadline
+        fallback_used: bool,
+        absent: Vec<HumanAddr>, // Revealers that didn't finalize before a fallback was used
     },
     RollingHash {
         hash: String,
     },
+    Reasons {
+        reasons: Vec<VoteReason>,
+        total: u32,
+    },
+    RollingHashHistory {
+        hashes: Vec<String>, // The rolling hash after each vote, in the order they were cast
+        total: u32,
+    },
+    VoteReceipt {
+        position: u32,        // Of the voter's latest vote in the rolling hash history
+        rolling_hash: String, // Right after the vote was included
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -1,24 +1,124 @@
-use crate::state::STAKING_POOL_KEY;
+use crate::state::{EXTRA_POOLS_KEY, STAKING_POOL_KEY};
 use cosmwasm_std::{
-    to_binary, Api, Extern, Querier, QueryRequest, StdError, StdResult, Storage, WasmQuery,
+    to_binary, Api, Extern, HumanAddr, Querier, QueryRequest, StdError, StdResult, Storage,
+    WasmQuery,
 };
 use scrt_finance::lp_staking_msg::{LPStakingQueryAnswer, LPStakingQueryMsg};
+use scrt_finance::math::mul_div;
+use scrt_finance::secret_vote_types::{WeightedPool, POOL_MULTIPLIER_SCALE};
 use scrt_finance::types::SecretContract;
 use secret_toolkit::storage::TypedStore;
 
+/// Returns the weighted total staked in all pools, as of `block` for snapshot polls
 pub fn query_staking_balance<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    block: Option<u64>,
 ) -> StdResult<u128> {
     let staking_pool: SecretContract = TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
+    let extra_pools: Vec<WeightedPool> = TypedStore::attach(&deps.storage).load(EXTRA_POOLS_KEY)?;
 
+    let mut balances = vec![query_total_locked(deps, staking_pool, block)?];
+    for extra_pool in &extra_pools {
+        balances.push(query_total_locked(deps, extra_pool.pool.clone(), block)?);
+    }
+
+    weighted_power(&extra_pools, &balances)
+}
+
+/// Returns the voter's unweighted power in each pool, the SEFI staking pool first. For snapshot
+/// polls it's the voting power as of `block`, including what was delegated to the voter
+pub fn query_voting_power<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: HumanAddr,
+    key: String,
+    block: Option<u64>,
+) -> StdResult<Vec<u128>> {
+    let staking_pool: SecretContract = TypedStore::attach(&deps.storage).load(STAKING_POOL_KEY)?;
+    let extra_pools: Vec<WeightedPool> = TypedStore::attach(&deps.storage).load(EXTRA_POOLS_KEY)?;
+
+    let mut powers =
+        vec![
+            query_pool_voting_power(deps, staking_pool, voter.clone(), key.clone(), block)?
+                .map_err(StdError::generic_err)?,
+        ];
+    // The same viewing key is used for every pool. An extra pool that doesn't accept it counts as
+    // zero, so voters without a stake there don't have to set a key. Any other error still fails
+    for extra_pool in extra_pools {
+        powers.push(
+            query_pool_voting_power(deps, extra_pool.pool, voter.clone(), key.clone(), block)?
+                .unwrap_or(0),
+        );
+    }
+
+    Ok(powers)
+}
+
+/// Weighs `powers`, which are ordered like the pools with the SEFI staking pool first
+pub fn weighted_power(extra_pools: &[WeightedPool], powers: &[u128]) -> StdResult<u128> {
+    let mut total = powers.first().copied().unwrap_or(0);
+    for (extra_pool, power) in extra_pools.iter().zip(powers.iter().skip(1)) {
+        let (weighted, _) = mul_div(*power, extra_pool.multiplier as u128, POOL_MULTIPLIER_SCALE)?;
+        total = total
+            .checked_add(weighted)
+            .ok_or_else(|| StdError::generic_err("voting power overflow"))?;
+    }
+
+    Ok(total)
+}
+
+fn query_total_locked<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    staking_pool: SecretContract,
+    block: Option<u64>,
+) -> StdResult<u128> {
+    let msg = match block {
+        Some(block) => LPStakingQueryMsg::TotalLockedAt { block },
+        None => LPStakingQueryMsg::TotalLocked {},
+    };
     let response = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
         callback_code_hash: staking_pool.contract_hash,
         contract_addr: staking_pool.address,
-        msg: to_binary(&LPStakingQueryMsg::TotalLocked {})?,
+        msg: to_binary(&msg)?,
     }))?;
 
     match response {
         LPStakingQueryAnswer::TotalLocked { amount } => Ok(amount.u128()),
+        LPStakingQueryAnswer::TotalLockedAt { amount } => Ok(amount.u128()),
+        _ => Err(StdError::generic_err(
+            "something is wrong with the lp staking contract..",
+        )),
+    }
+}
+
+/// The inner error is the pool's answer when it doesn't accept the voter's viewing key
+fn query_pool_voting_power<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    staking_pool: SecretContract,
+    voter: HumanAddr,
+    key: String,
+    block: Option<u64>,
+) -> StdResult<Result<u128, String>> {
+    let msg = match block {
+        Some(block) => LPStakingQueryMsg::VotingPowerAt {
+            address: voter,
+            key,
+            block,
+        },
+        None => LPStakingQueryMsg::VotingPower {
+            address: voter,
+            key,
+        },
+    };
+    let response = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        callback_code_hash: staking_pool.contract_hash,
+        contract_addr: staking_pool.address,
+        msg: to_binary(&msg)?,
+    }))?;
+
+    match response {
+        LPStakingQueryAnswer::VotingPower { amount } => Ok(Ok(amount.u128())),
+        LPStakingQueryAnswer::VotingPowerAt { amount } => Ok(Ok(amount.u128())),
+        LPStakingQueryAnswer::QueryError { msg } => Ok(Err(msg)),
         _ => Err(StdError::generic_err(
             "something is wrong with the lp staking contract..",
         )),
@@ -1,32 +1,75 @@
-use cosmwasm_std::{Api, Extern, HumanAddr, Querier, StdResult, Storage};
+use cosmwasm_std::{Api, Extern, HumanAddr, Querier, StdError, StdResult, Storage};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
 use schemars::JsonSchema;
-use scrt_finance::secret_vote_types::RevealCommittee;
-use secret_toolkit::storage::{TypedStore, TypedStoreMut};
+use scrt_finance::secret_vote_types::{Ballot, PollOutcome, RevealCommittee};
+use secret_toolkit::storage::{AppendStore, AppendStoreMut, TypedStore, TypedStoreMut};
 use serde::{Deserialize, Serialize};
 
 pub const OWNER_KEY: &[u8] = b"owner";
+pub const OWNER_HASH_KEY: &[u8] = b"ownerhash";
 pub const TALLY_KEY: &[u8] = b"tally";
+pub const TOTAL_POWER_KEY: &[u8] = b"totalpower";
+pub const RANKINGS_KEY: &[u8] = b"rankings";
+pub const RANKING_INDEX_KEY: &[u8] = b"rankingindex";
 pub const METADATA_KEY: &[u8] = b"metadata";
 pub const CONFIG_KEY: &[u8] = b"config";
 pub const STAKING_POOL_KEY: &[u8] = b"stakingpool";
 pub const NUM_OF_VOTERS_KEY: &[u8] = b"numofvoters";
+pub const NUM_OF_COMMITMENTS_KEY: &[u8] = b"numofcommitments";
 pub const REVEAL_CONFIG: &[u8] = b"revealconfig";
+pub const COMMITMENTS_KEY: &[u8] = b"commitments";
+pub const REASONS_KEY: &[u8] = b"reasons";
+pub const REASON_INDEX_KEY: &[u8] = b"reasonindex";
+pub const EXTRA_POOLS_KEY: &[u8] = b"extrapools";
+pub const POWER_SOURCES_KEY: &[u8] = b"powersources";
+pub const HASH_HISTORY_KEY: &[u8] = b"hashhistory";
+pub const RECEIPTS_KEY: &[u8] = b"receipts";
+pub const VOTER_COUNTS_KEY: &[u8] = b"votercounts";
+pub const FINAL_STAKED_KEY: &[u8] = b"finalstaked";
+pub const PRNG_SEED_KEY: &[u8] = b"prngseed";
+
+pub const VOTE_SPLIT_SCALE: u16 = 10_000;
+pub const TURNOUT_SCALE: u128 = 10_000; // Turnout is reported in basis points
+pub const MAX_REASON_LENGTH: usize = 280;
+pub const MAX_PAGE_SIZE: u32 = 100;
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct Vote {
-    pub choice: u8,
+    pub ballot: Ballot,
     pub voting_power: u128,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct StoredPollConfig {
-    pub end_timestamp: u64, // In seconds
-    pub quorum: u8,         // X/100% (percentage)
-    pub min_threshold: u8,  // X/100% (percentage)
+    pub start_timestamp: u64,              // In seconds
+    pub end_timestamp: u64,                // In seconds
+    pub reveal_end_timestamp: Option<u64>, // In seconds, only set for commit-reveal polls
+    pub quorum: u8,                        // X/100% (percentage)
+    pub min_threshold: u8,                 // X/100% (percentage)
+    pub quorum_inclusive: bool,
+    pub threshold_inclusive: bool,
     pub choices: Vec<String>,
     pub finalized: bool,
     pub valid: bool,
+    pub outcome: Option<PollOutcome>,
     pub rolling_hash: [u8; 32],
+    pub cancelled: bool,
+    pub snapshot_block: Option<u64>, // Voting power comes from staking checkpoints at this block
+    #[serde(default)]
+    pub pass_choice: u8,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Commitment {
+    pub hash: Vec<u8>,
+    pub voting_power: u128, // As of the commit
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredReason {
+    pub voter: HumanAddr,
+    pub reason: String,
+    pub disclose_voter: bool,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -34,18 +77,21 @@ pub struct StoredRevealConfig {
     pub committee: RevealCommittee,
     pub num_revealed: u64,
     pub revealed: Vec<HumanAddr>,
+    pub deadline: Option<u64>, // In seconds, after which the fallback quorum applies
+    pub fallback_used: bool,
+    pub absent: Vec<HumanAddr>,
 }
 
 pub fn store_vote<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     voter: &HumanAddr,
-    choice: u8,
+    ballot: Ballot,
     voting_power: u128,
 ) -> StdResult<()> {
     TypedStoreMut::attach(&mut deps.storage).store(
         voter.0.as_bytes(),
         &Vote {
-            choice,
+            ballot,
             voting_power,
         },
     )?;
@@ -59,3 +105,229 @@ pub fn read_vote<S: Storage, A: Api, Q: Querier>(
 ) -> StdResult<Vote> {
     Ok(TypedStore::attach(&deps.storage).load(voter.0.as_bytes())?)
 }
+
+pub fn store_commitment<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    voter: &HumanAddr,
+    commitment: &Commitment,
+) -> StdResult<()> {
+    let mut commitments_store = PrefixedStorage::new(COMMITMENTS_KEY, &mut deps.storage);
+    TypedStoreMut::attach(&mut commitments_store).store(voter.0.as_bytes(), commitment)
+}
+
+pub fn read_commitment<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: &HumanAddr,
+) -> StdResult<Option<Commitment>> {
+    let commitments_store = ReadonlyPrefixedStorage::new(COMMITMENTS_KEY, &deps.storage);
+    TypedStore::attach(&commitments_store).may_load(voter.0.as_bytes())
+}
+
+pub fn remove_commitment<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    voter: &HumanAddr,
+) {
+    let mut commitments_store = PrefixedStorage::new(COMMITMENTS_KEY, &mut deps.storage);
+    TypedStoreMut::<Commitment, _>::attach(&mut commitments_store).remove(voter.0.as_bytes());
+}
+
+/// Stores the unweighted power a voter has in each pool, the SEFI staking pool first
+pub fn store_power_sources<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    voter: &HumanAddr,
+    powers: &[u128],
+) -> StdResult<()> {
+    let mut sources_store = PrefixedStorage::new(POWER_SOURCES_KEY, &mut deps.storage);
+    TypedStoreMut::attach(&mut sources_store).store(voter.0.as_bytes(), &powers.to_vec())
+}
+
+pub fn read_power_sources<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: &HumanAddr,
+) -> StdResult<Option<Vec<u128>>> {
+    let sources_store = ReadonlyPrefixedStorage::new(POWER_SOURCES_KEY, &deps.storage);
+    TypedStore::attach(&sources_store).may_load(voter.0.as_bytes())
+}
+
+/// Stores a voter's reason, replacing the one they gave with a previous vote. A new reason is
+/// shuffled into a position picked from `entropy`, so the published order doesn't reveal the
+/// order in which people voted
+pub fn store_reason<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    reason: StoredReason,
+    entropy: &[u8; 32],
+) -> StdResult<()> {
+    let index_store = ReadonlyPrefixedStorage::new(REASON_INDEX_KEY, &deps.storage);
+    let index: Option<u32> =
+        TypedStore::attach(&index_store).may_load(reason.voter.0.as_bytes())?;
+
+    let mut reasons_store = PrefixedStorage::new(REASONS_KEY, &mut deps.storage);
+    let mut reasons_store = AppendStoreMut::attach_or_create(&mut reasons_store)?;
+    let voter = reason.voter.clone();
+    if let Some(index) = index {
+        reasons_store.set_at(index, &reason)?;
+        return Ok(());
+    }
+
+    // Inside-out shuffle: the new reason takes a random position and the one it displaces moves
+    // to the end
+    let len = reasons_store.len();
+    let mut seed = [0u8; 8];
+    seed.copy_from_slice(&entropy[..8]);
+    let index = (u64::from_le_bytes(seed) % (len as u64 + 1)) as u32;
+    let mut moved = vec![(voter, index)];
+    if index == len {
+        reasons_store.push(&reason)?;
+    } else {
+        let displaced = reasons_store.get_at(index)?;
+        reasons_store.push(&displaced)?;
+        reasons_store.set_at(index, &reason)?;
+        moved.push((displaced.voter, len));
+    }
+
+    let mut index_store = PrefixedStorage::new(REASON_INDEX_KEY, &mut deps.storage);
+    let mut index_store = TypedStoreMut::attach(&mut index_store);
+    for (voter, index) in moved {
+        index_store.store(voter.0.as_bytes(), &index)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_reasons<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<StoredReason>, u32)> {
+    let page_size = page_size.min(MAX_PAGE_SIZE);
+    let start = page_start(page, page_size)?;
+    let reasons_store = ReadonlyPrefixedStorage::new(REASONS_KEY, &deps.storage);
+    let reasons_store = if let Some(result) = AppendStore::<StoredReason, _>::attach(&reasons_store)
+    {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    let reasons: StdResult<Vec<StoredReason>> = reasons_store
+        .iter()
+        .skip(start)
+        .take(page_size as usize)
+        .collect();
+
+    Ok((reasons?, reasons_store.len()))
+}
+
+/// Adds voting power to, or removes it from, a full ranking. Each distinct ranking is indexed once
+/// when it first gets voting power, so a vote only touches its own ranking
+pub fn update_ranking<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    ranking: &[u8],
+    power: u128,
+    add: bool,
+) -> StdResult<()> {
+    let rankings_store = ReadonlyPrefixedStorage::new(RANKINGS_KEY, &deps.storage);
+    let current: Option<u128> = TypedStore::attach(&rankings_store).may_load(ranking)?;
+    let updated = match current {
+        Some(current) if add => current + power, // Can't overflow, bounded by the total power
+        Some(current) => current - power, // Can't underflow, `current` includes the removed vote
+        None if add && power > 0 => {
+            let mut index_store = PrefixedStorage::new(RANKING_INDEX_KEY, &mut deps.storage);
+            AppendStoreMut::attach_or_create(&mut index_store)?.push(&ranking.to_vec())?;
+            power
+        }
+        None => return Ok(()),
+    };
+
+    // Rankings that lost all of their power stay indexed, and are skipped when reading
+    let mut rankings_store = PrefixedStorage::new(RANKINGS_KEY, &mut deps.storage);
+    TypedStoreMut::attach(&mut rankings_store).store(ranking, &updated)
+}
+
+pub fn read_rankings<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> StdResult<Vec<(Vec<u8>, u128)>> {
+    let index_store = ReadonlyPrefixedStorage::new(RANKING_INDEX_KEY, &deps.storage);
+    let index_store = if let Some(result) = AppendStore::<Vec<u8>, _>::attach(&index_store) {
+        result?
+    } else {
+        return Ok(vec![]);
+    };
+
+    let rankings_store = ReadonlyPrefixedStorage::new(RANKINGS_KEY, &deps.storage);
+    let rankings_store = TypedStore::<u128, _>::attach(&rankings_store);
+    let mut rankings = vec![];
+    for ranking in index_store.iter() {
+        let ranking = ranking?;
+        let power = rankings_store.load(&ranking)?;
+        if power > 0 {
+            rankings.push((ranking, power));
+        }
+    }
+
+    Ok(rankings)
+}
+
+/// Records the rolling hash after `voter`'s vote was included, returns its position in the chain
+pub fn push_rolling_hash<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    voter: &HumanAddr,
+    hash: [u8; 32],
+) -> StdResult<u32> {
+    let mut history_store = PrefixedStorage::new(HASH_HISTORY_KEY, &mut deps.storage);
+    let mut history_store = AppendStoreMut::attach_or_create(&mut history_store)?;
+    let position = history_store.len();
+    history_store.push(&hash)?;
+
+    // Only the latest vote counts, so that's the one a receipt points to
+    let mut receipts_store = PrefixedStorage::new(RECEIPTS_KEY, &mut deps.storage);
+    TypedStoreMut::attach(&mut receipts_store).store(voter.0.as_bytes(), &position)?;
+
+    Ok(position)
+}
+
+pub fn read_receipt<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    voter: &HumanAddr,
+) -> StdResult<Option<(u32, [u8; 32])>> {
+    let receipts_store = ReadonlyPrefixedStorage::new(RECEIPTS_KEY, &deps.storage);
+    let position: Option<u32> = TypedStore::attach(&receipts_store).may_load(voter.0.as_bytes())?;
+    let position = match position {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+
+    let history_store = ReadonlyPrefixedStorage::new(HASH_HISTORY_KEY, &deps.storage);
+    let history_store = AppendStore::<[u8; 32], _>::attach(&history_store)
+        .unwrap_or_else(|| Err(StdError::generic_err("rolling hash history is empty")))?;
+    Ok(Some((position, history_store.get_at(position)?)))
+}
+
+pub fn read_hash_history<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<[u8; 32]>, u32)> {
+    let page_size = page_size.min(MAX_PAGE_SIZE);
+    let start = page_start(page, page_size)?;
+    let history_store = ReadonlyPrefixedStorage::new(HASH_HISTORY_KEY, &deps.storage);
+    let history_store = if let Some(result) = AppendStore::<[u8; 32], _>::attach(&history_store) {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    let hashes: StdResult<Vec<[u8; 32]>> = history_store
+        .iter()
+        .skip(start)
+        .take(page_size as usize)
+        .collect();
+
+    Ok((hashes?, history_store.len()))
+}
+
+fn page_start(page: u32, page_size: u32) -> StdResult<usize> {
+    page.checked_mul(page_size)
+        .map(|start| start as usize)
+        .ok_or_else(|| StdError::generic_err("page is out of range"))
+}
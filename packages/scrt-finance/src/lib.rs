@@ -1,5 +1,6 @@
 pub mod lp_staking_msg;
 pub mod master_msg;
+pub mod math;
 pub mod secret_vote_types;
 pub mod types;
 pub mod utils;
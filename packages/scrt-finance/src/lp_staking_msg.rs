@@ -13,6 +13,9 @@ pub struct LPStakingInitMsg {
     pub token_info: TokenInfo,
     pub prng_seed: Binary,
     pub subscribers: Option<Vec<SecretContract>>,
+    pub reward_start_block: Option<u64>,
+    pub reward_end_block: Option<u64>,
+    pub referral_share: Option<u16>, // In basis points (X/10000)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -30,6 +33,10 @@ pub enum LPStakingHandleMsg {
         padding: Option<String>,
     },
     EmergencyRedeem {},
+    Delegate {
+        to: HumanAddr,
+    },
+    Undelegate {},
 
     // Registered commands
     Receive {
@@ -57,6 +64,20 @@ pub enum LPStakingHandleMsg {
     RemoveRewardSources {
         contracts: Vec<HumanAddr>,
     },
+    SetRewardBlocks {
+        start_block: Option<u64>,
+        end_block: Option<u64>,
+        // Rewards keep accruing without an end block
+        #[serde(default)]
+        remove_end_block: bool,
+    },
+    ClaimResidual {
+        recipient: Option<HumanAddr>,
+        amount: Option<Uint128>,
+    },
+    SetReferralShare {
+        share: u16, // In basis points (X/10000)
+    },
 
     // Master callbacks
     NotifyAllocation {
@@ -85,12 +106,20 @@ pub enum LPStakingHandleAnswer {
     RemoveSubs { status: LPStakingResponseStatus },
     AddRewardSources { status: LPStakingResponseStatus },
     RemoveRewardSources { status: LPStakingResponseStatus },
+    SetRewardBlocks { status: LPStakingResponseStatus },
+    ClaimResidual { status: LPStakingResponseStatus },
+    SetReferralShare { status: LPStakingResponseStatus },
+    Delegate { status: LPStakingResponseStatus },
+    Undelegate { status: LPStakingResponseStatus },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum LPStakingReceiveMsg {
-    Deposit {},
+    Deposit {
+        // Only recorded on a staker's first deposit
+        referrer: Option<HumanAddr>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -99,6 +128,7 @@ pub enum LPStakingHookMsg {
     Deposit {
         from: HumanAddr,
         amount: Uint128,
+        referrer: Option<HumanAddr>,
     },
     Redeem {
         to: HumanAddr,
@@ -124,6 +154,12 @@ pub enum LPStakingQueryMsg {
     TotalLocked {},
     Subscribers {},
     RewardSources {},
+    PoolStatus {
+        block: u64,
+    },
+    TotalLockedAt {
+        block: u64,
+    },
 
     // Authenticated
     Rewards {
@@ -135,6 +171,28 @@ pub enum LPStakingQueryMsg {
         address: HumanAddr,
         key: String,
     },
+    ReferralStats {
+        address: HumanAddr,
+        key: String,
+    },
+    BalanceAt {
+        address: HumanAddr,
+        key: String,
+        block: u64,
+    },
+    VotingPower {
+        address: HumanAddr,
+        key: String,
+    },
+    VotingPowerAt {
+        address: HumanAddr,
+        key: String,
+        block: u64,
+    },
+    Delegation {
+        address: HumanAddr,
+        key: String,
+    },
 }
 
 impl LPStakingQueryMsg {
@@ -142,6 +200,13 @@ impl LPStakingQueryMsg {
         match self {
             LPStakingQueryMsg::Rewards { address, key, .. } => (address, ViewingKey(key.clone())),
             LPStakingQueryMsg::Balance { address, key } => (address, ViewingKey(key.clone())),
+            LPStakingQueryMsg::ReferralStats { address, key } => (address, ViewingKey(key.clone())),
+            LPStakingQueryMsg::BalanceAt { address, key, .. } => (address, ViewingKey(key.clone())),
+            LPStakingQueryMsg::VotingPower { address, key } => (address, ViewingKey(key.clone())),
+            LPStakingQueryMsg::VotingPowerAt { address, key, .. } => {
+                (address, ViewingKey(key.clone()))
+            }
+            LPStakingQueryMsg::Delegation { address, key } => (address, ViewingKey(key.clone())),
             _ => panic!("This should never happen"),
         }
     }
@@ -177,12 +242,41 @@ pub enum LPStakingQueryAnswer {
     TotalLocked {
         amount: Uint128,
     },
+    TotalLockedAt {
+        amount: Uint128,
+    },
+    BalanceAt {
+        amount: Uint128,
+    },
+    VotingPower {
+        amount: Uint128,
+    },
+    VotingPowerAt {
+        amount: Uint128,
+    },
+    Delegation {
+        delegate: Option<HumanAddr>,
+        delegated_power: Uint128, // Power delegated to this address by others
+        voting_power: Uint128,
+    },
     Subscribers {
         contracts: Vec<SecretContract>,
     },
     RewardSources {
         contracts: Vec<SecretContract>,
     },
+    ReferralStats {
+        referrer: Option<HumanAddr>,
+        referees: u64,
+        referred_stake: Uint128,
+        rewards_earned: Uint128,
+    },
+    PoolStatus {
+        status: LPStakingPoolStatus,
+        reward_start_block: u64,
+        reward_end_block: Option<u64>,
+        residual: Uint128,
+    },
 
     QueryError {
         msg: String,
@@ -195,3 +289,12 @@ pub enum LPStakingResponseStatus {
     Success,
     Failure,
 }
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LPStakingPoolStatus {
+    NotStarted,
+    Active,
+    Ended,
+    Stopped,
+}
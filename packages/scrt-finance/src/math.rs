@@ -0,0 +1,139 @@
+use cosmwasm_std::{StdError, StdResult};
+
+const LOW_MASK: u128 = u64::MAX as u128;
+
+// U256 is an unsigned 256-bit integer, used as an intermediate value for u128 multiplications
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct U256 {
+    pub hi: u128,
+    pub lo: u128,
+}
+
+impl U256 {
+    pub fn full_mul(a: u128, b: u128) -> Self {
+        let (a_hi, a_lo) = (a >> 64, a & LOW_MASK);
+        let (b_hi, b_lo) = (b >> 64, b & LOW_MASK);
+
+        // Every partial product fits in a u128 since its factors are at most 64 bits wide
+        let lo_lo = a_lo * b_lo;
+        let lo_hi = a_lo * b_hi;
+        let hi_lo = a_hi * b_lo;
+        let hi_hi = a_hi * b_hi;
+
+        let mid = (lo_lo >> 64) + (lo_hi & LOW_MASK) + (hi_lo & LOW_MASK);
+
+        Self {
+            hi: hi_hi + (lo_hi >> 64) + (hi_lo >> 64) + (mid >> 64),
+            lo: (lo_lo & LOW_MASK) | (mid << 64),
+        }
+    }
+
+    /// Returns the quotient and the remainder, or `None` if `divisor` is 0
+    pub fn checked_div_rem(self, divisor: u128) -> Option<(Self, u128)> {
+        if divisor == 0 {
+            return None;
+        }
+
+        if self.hi == 0 {
+            let quotient = Self {
+                hi: 0,
+                lo: self.lo / divisor,
+            };
+            return Some((quotient, self.lo % divisor));
+        }
+
+        // Binary long division, one bit at a time from the most significant one
+        let mut quotient = Self::default();
+        let mut remainder: u128 = 0;
+        for i in (0..256).rev() {
+            let carry = remainder >> 127;
+            remainder = (remainder << 1) | self.bit(i);
+
+            // If the shift carried out a bit, the remainder is certainly larger than the divisor
+            if carry == 1 || remainder >= divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.set_bit(i);
+            }
+        }
+
+        Some((quotient, remainder))
+    }
+
+    pub fn to_u128(self) -> Option<u128> {
+        if self.hi == 0 {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    fn bit(&self, i: u32) -> u128 {
+        if i < 128 {
+            (self.lo >> i) & 1
+        } else {
+            (self.hi >> (i - 128)) & 1
+        }
+    }
+
+    fn set_bit(&mut self, i: u32) {
+        if i < 128 {
+            self.lo |= 1 << i;
+        } else {
+            self.hi |= 1 << (i - 128);
+        }
+    }
+}
+
+/// Calculates `a * b / c` without overflowing on the intermediate product. Returns the quotient
+/// along with the remainder of the division
+pub fn mul_div(a: u128, b: u128, c: u128) -> StdResult<(u128, u128)> {
+    let (quotient, remainder) = U256::full_mul(a, b)
+        .checked_div_rem(c)
+        .ok_or_else(|| StdError::generic_err("division by zero"))?;
+
+    let quotient = quotient.to_u128().ok_or_else(|| {
+        StdError::generic_err(format!("overflow when calculating {} * {} / {}", a, b, c))
+    })?;
+
+    Ok((quotient, remainder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_mul() {
+        assert_eq!(U256::full_mul(6, 7), U256 { hi: 0, lo: 42 });
+        assert_eq!(
+            U256::full_mul(u128::MAX, u128::MAX),
+            U256 {
+                hi: u128::MAX - 1,
+                lo: 1
+            }
+        );
+        assert_eq!(U256::full_mul(1 << 127, 4), U256 { hi: 2, lo: 0 });
+    }
+
+    #[test]
+    fn test_mul_div() {
+        assert_eq!(mul_div(10, 20, 3).unwrap(), (66, 2));
+
+        // The intermediate product overflows u128, but the result fits
+        let scale = 1_000_000_000_000_000_000;
+        let amount = 1_000_000_000_000_000_000_000_000_000; // 10 ^ 27
+        assert_eq!(mul_div(amount, scale, scale).unwrap(), (amount, 0));
+        assert_eq!(
+            mul_div(u128::MAX, u128::MAX, u128::MAX).unwrap(),
+            (u128::MAX, 0)
+        );
+        assert_eq!(
+            mul_div(u128::MAX, 3, 2).unwrap_err(),
+            StdError::generic_err(format!("overflow when calculating {} * 3 / 2", u128::MAX))
+        );
+        assert_eq!(
+            mul_div(1, 1, 0).unwrap_err(),
+            StdError::generic_err("division by zero")
+        );
+    }
+}
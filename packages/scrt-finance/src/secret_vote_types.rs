@@ -1,5 +1,5 @@
 use crate::types::SecretContract;
-use cosmwasm_std::{Binary, HumanAddr, Uint128};
+use cosmwasm_std::{Binary, CosmosMsg, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -11,24 +11,138 @@ pub struct PollContract {
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct PollConfig {
-    pub duration: u64,     // In seconds
-    pub quorum: u8,        // X/100% (percentage)
-    pub min_threshold: u8, // X/100% (percentage)
+    pub duration: u64,              // In seconds
+    pub quorum: u8,                 // X/100% (percentage) of the total staked amount
+    pub min_threshold: u8,          // X/100% (percentage) of the votes cast
+    pub reveal_period: Option<u64>, // In seconds, enables commit-reveal voting
+    pub voting_delay: Option<u64>,  // In seconds, a discussion period before voting opens
+    // Whether participation of exactly `quorum` is enough
+    #[serde(default)]
+    pub quorum_inclusive: bool,
+    // Whether a winning share of exactly `min_threshold` is enough
+    #[serde(default)]
+    pub threshold_inclusive: bool,
+    // Voting power as of the block before the poll's creation, without live updates
+    #[serde(default)]
+    pub snapshot_voting: bool,
+    // The affirmative choice, the poll only passes if it wins. The first choice if not set
+    #[serde(default)]
+    pub pass_choice: u8,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct PollMetadata {
     pub title: String,
     pub description: String,
-    pub vote_type: String,
+    pub vote_type: VoteType,
     pub author_addr: Option<HumanAddr>,
     pub author_alias: String,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteType {
+    SingleChoice,
+    Approval,
+    RankedChoice, // Tallied by instant runoff
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Ballot {
+    SingleChoice { choice: u8 }, // Arbitrary id that is given by the contract
+    Approval { choices: u64 },   // Bitmask, bit `i` approves choice `i`
+    RankedChoice { ranking: Vec<u8> }, // Most preferred choice first
+    Split { allocations: Vec<(u8, u16)> }, // (choice, basis points > 0), for single choice polls
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct RevealCommittee {
     pub n: u64,
     pub revealers: Vec<HumanAddr>,
+    pub timeout: Option<u64>, // In seconds after voting (or revealing) ends
+    pub fallback_n: Option<u64>, // Required after the timeout, `None` lets anyone finalize
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollOutcome {
+    Passed,
+    Rejected,
+    QuorumNotReached,
+}
+
+impl PollOutcome {
+    // Matches the serialized form, used as the `status` log attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PollOutcome::Passed => "passed",
+            PollOutcome::Rejected => "rejected",
+            PollOutcome::QuorumNotReached => "quorum_not_reached",
+        }
+    }
+}
+
+// Log attributes emitted by the poll factory and the polls, so indexers can follow a poll's
+// lifecycle without parsing messages
+pub mod log_keys {
+    pub const ACTION: &str = "action";
+    pub const POLL_ID: &str = "poll_id";
+    pub const POLL_ADDR: &str = "poll_addr";
+    pub const STATUS: &str = "status";
+    pub const AUTHOR: &str = "author";
+    pub const VOTER: &str = "voter";
+    pub const LABEL: &str = "label";
+    pub const TEMPLATE: &str = "template";
+}
+
+// Values of the `action` log attribute
+pub mod log_actions {
+    pub const NEW_POLL: &str = "new_poll";
+    pub const REGISTER_POLL: &str = "register_poll";
+    pub const VOTE: &str = "vote";
+    pub const COMMIT_VOTE: &str = "commit_vote";
+    pub const REVEAL_VOTE: &str = "reveal_vote";
+    pub const UPDATE_VOTING_POWER: &str = "update_voting_power";
+    pub const FINALIZE: &str = "finalize";
+    pub const EXECUTE: &str = "execute";
+    pub const CANCEL: &str = "cancel";
+    pub const SETTLE_DEPOSIT: &str = "settle_deposit";
+    pub const UPDATE_CONFIG: &str = "update_config";
+    pub const UPDATE_DEFAULT_POLL_CONFIG: &str = "update_default_poll_config";
+    pub const SET_TEMPLATE: &str = "set_template";
+    pub const REMOVE_TEMPLATE: &str = "remove_template";
+    pub const REMOVE_GUARDIAN: &str = "remove_guardian";
+    pub const CHANGE_ADMIN: &str = "change_admin";
+}
+
+// `None` means no limit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Default)]
+pub struct PollLimits {
+    pub max_active_polls: Option<u32>,
+    pub max_active_polls_per_author: Option<u32>,
+    pub author_cooldown: Option<u64>, // In seconds between two polls of the same author
+    pub max_voting_delay: Option<u64>, // In seconds
+    pub max_duration: Option<u64>,    // In seconds
+    pub max_reveal_period: Option<u64>, // In seconds
+}
+
+// Executable proposals are only accepted from polls that are at least this strict, and can only
+// call the allowed contracts
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExecutionRules {
+    pub min_quorum: u8,
+    pub min_threshold: u8,
+    pub allowed_targets: Vec<HumanAddr>,
+}
+
+pub const POOL_MULTIPLIER_SCALE: u128 = 10_000;
+
+// A staking contract besides the SEFI staking pool whose stake also counts as voting power
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct WeightedPool {
+    pub pool: SecretContract,
+    pub multiplier: u32, // In basis points (X/10000), 10000 counts the stake at 1x
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -38,24 +152,40 @@ pub struct PollInitMsg {
     pub reveal_com: RevealCommittee,
     pub choices: Vec<String>,
     pub staking_pool: SecretContract,
+    pub extra_pools: Vec<WeightedPool>,
     pub init_hook: Option<InitHook>,
+    pub prng_seed: Binary, // Kept private by the poll, so the order of published reasons can't be worked out
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum PollHandleMsg {
     Vote {
-        choice: u8, // Arbitrary id that is given by the contract
+        ballot: Ballot, // Must match the poll's vote type
         staking_pool_viewing_key: String,
         salt: String,
+        reason: Option<String>,       // Published after finalization
+        disclose_voter: Option<bool>, // Whether the reason is published along with the voter's address
     },
     UpdateVotingPower {
         voter: HumanAddr,
         new_power: Uint128,
+        pool: Option<HumanAddr>, // The pool whose stake changed, the SEFI staking pool if not set
+    },
+    CommitVote {
+        // sha256(voter || ballot bytes || salt), with the ballot encoded as in the rolling hash
+        commitment: Binary,
+        staking_pool_viewing_key: String,
+    },
+    RevealVote {
+        ballot: Ballot,
+        salt: String,
     },
     Finalize {
-        rolling_hash: String,
+        rolling_hash: String, // Hex encoded, see the secret-poll README for how it's built
     },
+    Execute {},
+    Cancel {}, // By the author before any votes and before voting ends, or by the factory's guardian
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -69,9 +199,19 @@ pub struct InitHook {
 pub enum PollFactoryHandleMsg {
     NewPoll {
         poll_metadata: PollMetadata,
-        poll_config: Option<PollConfig>,
+        template: Option<String>,
+        poll_config: Option<PollConfig>, // Can only be stricter than the template's config
         poll_choices: Vec<String>,
         pool_viewing_key: String,
+        execute_msgs: Option<Vec<CosmosMsg>>, // Dispatched by the factory if the poll passes
+    },
+
+    // Registered commands
+    Receive {
+        sender: HumanAddr,
+        from: HumanAddr,
+        amount: Uint128,
+        msg: Binary,
     },
 
     // Staking contract callback
@@ -83,22 +223,93 @@ pub enum PollFactoryHandleMsg {
     // Poll contract callback
     RegisterForUpdates {
         challenge: String,
+        poll_id: u64,
         end_time: u64,
     },
+    RegisterVoter {
+        voter: HumanAddr,
+    },
+    ExecutePoll {},
+    PollFinalized {
+        outcome: PollOutcome,
+    },
+    PollCancelled {},
 
     // Admin
     UpdateDefaultPollConfig {
         duration: Option<u64>,     // In seconds
         quorum: Option<u8>,        // X/100% (percentage)
         min_threshold: Option<u8>, // X/100% (percentage)
+        quorum_inclusive: Option<bool>,
+        threshold_inclusive: Option<bool>,
+        reveal_period: Option<u64>, // 0 disables commit-reveal voting
+        voting_delay: Option<u64>,  // 0 opens voting right away
+        snapshot_voting: Option<bool>,
     },
     UpdateConfig {
         new_poll_code: Option<PollContract>,
         new_staking_pool: Option<SecretContract>,
         new_min_stake_amount: Option<Uint128>,
         new_reveal_com: Option<RevealCommittee>,
+        new_deposit: Option<DepositConfig>,
+        new_limits: Option<PollLimits>,
+        new_guardian: Option<HumanAddr>,
+        new_extra_pools: Option<Vec<WeightedPool>>,
+        new_execution_rules: Option<ExecutionRules>,
     },
     ChangeAdmin {
         new_admin: HumanAddr,
     },
+    SetTemplate {
+        name: String,
+        template: PollTemplate,
+    },
+    RemoveTemplate {
+        name: String,
+    },
+    RemoveGuardian {},
+
+    // Guardian
+    CancelPoll {
+        poll: HumanAddr,
+    },
+
+    // Slashes the deposit of a poll that was never finalized. The admin can do it once the poll's
+    // reveal and committee deadline passed, anyone else once the settlement timeout has passed too
+    SettleDeposit {
+        poll: HumanAddr,
+    },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PollFactoryReceiveMsg {
+    // The sent amount is held as the poll's deposit
+    NewPoll {
+        poll_metadata: PollMetadata,
+        template: Option<String>,
+        poll_config: Option<PollConfig>,
+        poll_choices: Vec<String>,
+        pool_viewing_key: String,
+        execute_msgs: Option<Vec<CosmosMsg>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct DepositConfig {
+    pub token: SecretContract,
+    pub amount: Uint128,
+    pub slash_recipient: Option<HumanAddr>, // E.g. the dev-fund, deposits are burned if not set
+    // Deposits are refunded once quorum is reached, so polls that take one can't set it lower
+    #[serde(default)]
+    pub min_quorum: u8,
+}
+
+// Polls created from a template can only use a stricter quorum, threshold, duration and delay
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct PollTemplate {
+    pub config: PollConfig,
+    pub min_staked: Option<Uint128>, // Overrides the factory's minimum stake
+    pub vote_type: Option<VoteType>,
+    pub allowed_choices: Option<Vec<String>>,
 }